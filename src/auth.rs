@@ -0,0 +1,219 @@
+use crate::progress::Transfer;
+use anyhow::{anyhow, Result};
+use git2::{Cred, CredentialType, ErrorCode, FetchOptions, RemoteCallbacks};
+use std::cell::RefCell;
+use std::env;
+use std::path::PathBuf;
+
+// Personal access tokens for https remotes, checked in this order, and the host
+// each one is sent to
+const TOKEN_VAR: &str = "IPAC_GIT_TOKEN";
+const TOKEN_HOST_VAR: &str = "IPAC_GIT_TOKEN_HOST";
+const GITHUB_TOKEN_VAR: &str = "GITHUB_TOKEN";
+const GITHUB_HOST: &str = "github.com";
+
+// Private key used for ssh remotes before the usual ~/.ssh keys
+const SSH_KEY_VAR: &str = "IPAC_SSH_KEY";
+const SSH_PASSPHRASE_VAR: &str = "IPAC_SSH_PASSPHRASE";
+
+// Key files ssh would try by default, in ~/.ssh
+const DEFAULT_SSH_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+// Error the credential callback gives once every method has been rejected
+const NO_MORE_CREDENTIALS: &str = "no more credentials to try";
+
+// Run a clone or fetch with credential callbacks for private repositories, reporting
+// its progress to `transfer`
+//
+// git2 calls the callback again after every rejected credential, so each method is
+// offered once: ssh-agent and key files for ssh remotes, then a token from the
+// environment and git's credential helpers for https remotes.
+pub fn with_credentials<T>(
+    url: &str,
+    transfer: &Transfer,
+    operation: impl FnOnce(FetchOptions<'_>) -> Result<T, git2::Error>,
+) -> Result<T> {
+    let tried = RefCell::new(Vec::new());
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(|url, username, allowed| {
+        next_credential(url, username, allowed, &mut tried.borrow_mut())
+    });
+    callbacks.transfer_progress(|stats| transfer.objects(&stats));
+    callbacks.sideband_progress(|_| !transfer.interrupted());
+    let mut fetch_options = FetchOptions::new();
+    fetch_options.remote_callbacks(callbacks);
+
+    operation(fetch_options).map_err(|err| {
+        let tried = tried.borrow();
+        if is_auth_error(&err) {
+            let reason = match err.message() {
+                NO_MORE_CREDENTIALS => String::new(),
+                message => format!(": {}", message),
+            };
+            anyhow!(
+                "Authentication failed for {} ({}){}\n\
+                 Load your key into ssh-agent or set {} for ssh remotes, \
+                 or set {} to a personal access token for https remotes \
+                 ({} names its host when that is not {})",
+                url,
+                describe_tried(&tried),
+                reason,
+                SSH_KEY_VAR,
+                TOKEN_VAR,
+                TOKEN_HOST_VAR,
+                GITHUB_HOST
+            )
+        } else {
+            err.into()
+        }
+    })
+}
+
+// Offer the first credential that has not been tried yet
+fn next_credential(
+    url: &str,
+    username: Option<&str>,
+    allowed: CredentialType,
+    tried: &mut Vec<String>,
+) -> Result<Cred, git2::Error> {
+    let user = username.unwrap_or("git");
+
+    if allowed.contains(CredentialType::USERNAME) && !try_once(tried, "username") {
+        return Cred::username(user);
+    }
+
+    if allowed.contains(CredentialType::SSH_KEY) {
+        if !try_once(tried, "ssh-agent") {
+            return Cred::ssh_key_from_agent(user);
+        }
+        let passphrase = env::var(SSH_PASSPHRASE_VAR).ok();
+        for key in ssh_keys() {
+            if !try_once(tried, &format!("key {}", key.display())) {
+                return Cred::ssh_key(user, None, &key, passphrase.as_deref());
+            }
+        }
+    }
+
+    if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+        for var in token_vars(url) {
+            let Ok(token) = env::var(var) else { continue };
+            if !token.is_empty() && !try_once(tried, var) {
+                let user = username.unwrap_or("x-access-token");
+                return Cred::userpass_plaintext(user, &token);
+            }
+        }
+        if !try_once(tried, "credential helper") {
+            if let Ok(cred) = git2::Config::open_default()
+                .and_then(|config| Cred::credential_helper(&config, url, username))
+            {
+                return Ok(cred);
+            }
+        }
+    }
+
+    Err(git2::Error::from_str(NO_MORE_CREDENTIALS))
+}
+
+// Token variables that may be sent to the remote at `url`
+//
+// A token only goes to the host it is for, so a dependency hosted elsewhere cannot
+// collect it by asking for credentials: `GITHUB_TOKEN` to github.com and
+// `IPAC_GIT_TOKEN` to the host in `IPAC_GIT_TOKEN_HOST`, github.com by default.
+fn token_vars(url: &str) -> Vec<&'static str> {
+    let Some(host) = https_host(url) else {
+        return Vec::new();
+    };
+    let token_host = env::var(TOKEN_HOST_VAR).unwrap_or_else(|_| GITHUB_HOST.to_string());
+    [
+        (TOKEN_VAR, token_host.as_str()),
+        (GITHUB_TOKEN_VAR, GITHUB_HOST),
+    ]
+    .into_iter()
+    .filter(|(_, token_host)| host.eq_ignore_ascii_case(token_host))
+    .map(|(var, _)| var)
+    .collect()
+}
+
+// Host of an https URL, without the user or port
+fn https_host(url: &str) -> Option<&str> {
+    let authority = url.strip_prefix("https://")?.split('/').next()?;
+    let host = authority
+        .rsplit_once('@')
+        .map_or(authority, |(_, host)| host);
+    host.split(':').next().filter(|host| !host.is_empty())
+}
+
+// Mark a method as tried, returning whether it already was
+fn try_once(tried: &mut Vec<String>, method: &str) -> bool {
+    if tried.iter().any(|entry| entry == method) {
+        return true;
+    }
+    tried.push(method.to_string());
+    false
+}
+
+// Key files to try: the one named in the environment, then the default ones that exist
+fn ssh_keys() -> Vec<PathBuf> {
+    let mut keys: Vec<PathBuf> = env::var_os(SSH_KEY_VAR)
+        .map(PathBuf::from)
+        .into_iter()
+        .collect();
+    if let Some(home) = dirs::home_dir() {
+        keys.extend(
+            DEFAULT_SSH_KEYS
+                .iter()
+                .map(|name| home.join(".ssh").join(name))
+                .filter(|key| key.exists()),
+        );
+    }
+    keys
+}
+
+// Whether git2 gave up because the remote rejected us
+fn is_auth_error(err: &git2::Error) -> bool {
+    err.code() == ErrorCode::Auth
+        || err.message() == NO_MORE_CREDENTIALS
+        || err.message().contains("authentication")
+}
+
+// Methods offered to the remote, for the error message
+fn describe_tried(tried: &[String]) -> String {
+    let methods: Vec<&str> = tried
+        .iter()
+        .map(String::as_str)
+        .filter(|method| *method != "username")
+        .collect();
+    if methods.is_empty() {
+        "no credentials were available".to_string()
+    } else {
+        format!("tried {}", methods.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_the_host_of_https_urls() {
+        assert_eq!(
+            https_host("https://github.com/owner/repo.git"),
+            Some("github.com")
+        );
+        assert_eq!(
+            https_host("https://user@git.example.org:8443/repo"),
+            Some("git.example.org")
+        );
+        assert_eq!(https_host("http://github.com/owner/repo"), None);
+        assert_eq!(https_host("git@github.com:owner/repo.git"), None);
+        assert_eq!(https_host("https:///repo"), None);
+    }
+
+    #[test]
+    fn github_token_only_goes_to_github() {
+        assert!(token_vars("https://github.com/owner/repo").contains(&GITHUB_TOKEN_VAR));
+        assert!(!token_vars("https://evil.example.com/repo").contains(&GITHUB_TOKEN_VAR));
+        assert!(token_vars("http://github.com/owner/repo").is_empty());
+    }
+}
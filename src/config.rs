@@ -0,0 +1,84 @@
+use crate::link::InstallMode;
+use anyhow::{Context, Result};
+use dirs::home_dir;
+use serde::Deserialize;
+use std::fs;
+use std::path::PathBuf;
+
+// Settings read from ~/.igor/config.toml
+//
+// Every field is optional; command line flags and environment variables take
+// precedence over anything set here.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    // Folder containing the "Igor Pro N Folder" installations
+    pub igor_dir: Option<PathBuf>,
+    // Folder containing the "Igor Pro N User Files" folders
+    pub user_files_dir: Option<PathBuf>,
+    // Extra Wine prefixes to search for Igor Pro on Linux and macOS
+    #[serde(default)]
+    pub wine_prefixes: Vec<PathBuf>,
+    // How `ipac install` places package files: "symlink", "copy" or "hardlink"
+    pub mode: Option<InstallMode>,
+}
+
+impl Config {
+    // Load the config file, returning the defaults when it does not exist
+    pub fn load() -> Result<Config> {
+        let path = config_path();
+        if !path.exists() {
+            return Ok(Config::default());
+        }
+
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {:?}", path))
+    }
+}
+
+// The user's $HOME/.igor folder where ipac keeps packages and state
+pub fn igor_home() -> PathBuf {
+    let home_dir = home_dir().expect("Could not find the user's home directory.");
+    home_dir.join(".igor")
+}
+
+// Location of the config file, overridable with IPAC_CONFIG
+fn config_path() -> PathBuf {
+    match std::env::var_os("IPAC_CONFIG") {
+        Some(path) => PathBuf::from(path),
+        None => igor_home().join("config.toml"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_kebab_case_keys() {
+        let config: Config = toml::from_str(
+            r#"
+            igor-dir = "D:/Portable/WaveMetrics"
+            user-files-dir = "//fileserver/WaveMetrics"
+            wine-prefixes = ["/home/me/.wine-igor9"]
+            mode = "copy"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.igor_dir,
+            Some(PathBuf::from("D:/Portable/WaveMetrics"))
+        );
+        assert_eq!(
+            config.wine_prefixes,
+            [PathBuf::from("/home/me/.wine-igor9")]
+        );
+        assert_eq!(config.mode, Some(InstallMode::Copy));
+    }
+
+    #[test]
+    fn rejects_snake_case_keys() {
+        assert!(toml::from_str::<Config>("igor_dir = \"D:/WaveMetrics\"").is_err());
+    }
+}
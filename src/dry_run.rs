@@ -0,0 +1,157 @@
+use crate::transaction::Operation;
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt;
+use std::path::PathBuf;
+
+// What a command does to a package as a whole
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PackageAction {
+    Install,
+    Reinstall,
+    Update,
+    Uninstall,
+}
+
+// A change to a package's clone under ~/.igor
+#[derive(Debug, Clone, Serialize)]
+#[serde(
+    tag = "action",
+    rename_all = "kebab-case",
+    rename_all_fields = "kebab-case"
+)]
+pub enum CloneChange {
+    // Clone `url` into `path`, ending up at `commit`
+    Clone {
+        url: String,
+        path: PathBuf,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        git_ref: Option<String>,
+        commit: Option<String>,
+    },
+    // Move an existing clone from one commit to another
+    Checkout {
+        path: PathBuf,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        git_ref: Option<String>,
+        from: Option<String>,
+        to: Option<String>,
+    },
+    // Delete the clone with `--remove-clone`
+    Remove {
+        path: PathBuf,
+    },
+}
+
+// Everything a command does to one package: its clone, then the User Files folders
+#[derive(Debug, Serialize)]
+pub struct PlannedPackage {
+    pub name: String,
+    pub version: String,
+    pub action: PackageAction,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub clone: Option<CloneChange>,
+    pub operations: Vec<Operation>,
+}
+
+impl fmt::Display for PackageAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PackageAction::Install => write!(f, "install"),
+            PackageAction::Reinstall => write!(f, "reinstall"),
+            PackageAction::Update => write!(f, "update"),
+            PackageAction::Uninstall => write!(f, "uninstall"),
+        }
+    }
+}
+
+impl fmt::Display for CloneChange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CloneChange::Clone {
+                url,
+                path,
+                git_ref,
+                commit,
+            } => {
+                write!(f, "Clone {} into {:?}", url, path)?;
+                if let Some(git_ref) = git_ref {
+                    write!(f, " at {}", git_ref)?;
+                }
+                write_commit(f, commit)
+            }
+            CloneChange::Checkout {
+                path,
+                git_ref,
+                from,
+                to,
+            } => {
+                write!(f, "Move {:?}", path)?;
+                write_commit(f, from)?;
+                write!(f, " to")?;
+                if let Some(git_ref) = git_ref {
+                    write!(f, " {}", git_ref)?;
+                }
+                write_commit(f, to)
+            }
+            CloneChange::Remove { path } => write!(f, "Delete the clone at {:?}", path),
+        }
+    }
+}
+
+// Short form of a commit, when there is one
+fn write_commit(f: &mut fmt::Formatter<'_>, commit: &Option<String>) -> fmt::Result {
+    match commit {
+        Some(commit) => write!(f, " ({:.8})", commit),
+        None => Ok(()),
+    }
+}
+
+// Print what a dry run found, as text or as JSON
+pub fn print_plan(packages: &[PlannedPackage], json: bool) -> Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(packages)?);
+        return Ok(());
+    }
+
+    if packages.is_empty() {
+        println!("Nothing to do.");
+        return Ok(());
+    }
+    for package in packages {
+        println!(
+            "Would {} {} {}",
+            package.action, package.name, package.version
+        );
+        if let Some(clone) = &package.clone {
+            println!("  {}", clone);
+        }
+        for operation in &package.operations {
+            println!("  {}", operation);
+        }
+        if package.clone.is_none() && package.operations.is_empty() {
+            println!("  Nothing to change");
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn clone_changes_use_kebab_case_keys() {
+        let change = CloneChange::Checkout {
+            path: PathBuf::from("/igor/pkg"),
+            git_ref: Some("main".to_string()),
+            from: None,
+            to: None,
+        };
+        let json = serde_json::to_value(&change).unwrap();
+        assert_eq!(json["action"], "checkout");
+        assert_eq!(json["git-ref"], "main");
+        assert!(json.get("git_ref").is_none());
+    }
+}
@@ -0,0 +1,175 @@
+use crate::git::{split_url_ref, split_url_version, CloneOptions, GitRef, GitSource};
+use crate::igor::VersionSelection;
+use crate::link::InstallMode;
+use anyhow::{bail, Context, Result};
+use semver::VersionReq;
+use serde::Deserialize;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Default name of the environment file read by `ipac sync`
+pub const ENV_FILE: &str = "igor-env.toml";
+
+// The packages an Igor environment should contain
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvFile {
+    #[serde(default, rename = "package")]
+    pub packages: Vec<EnvPackage>,
+}
+
+// One package of an environment file, from git or a local folder
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct EnvPackage {
+    pub git: Option<String>,
+    pub path: Option<PathBuf>,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub rev: Option<String>,
+    // Folder of the repository or local path that holds the package
+    pub subdir: Option<PathBuf>,
+    // Fetch only this many commits of history
+    pub depth: Option<u32>,
+    // Set to false to leave git submodules uninitialized
+    pub submodules: Option<bool>,
+    // Link, copy or hard link the files, defaulting to the config file's mode
+    pub mode: Option<InstallMode>,
+    // Igor Pro versions to install into, e.g. ["8", "9"] or ["all"]
+    #[serde(default)]
+    pub igor_versions: Vec<String>,
+}
+
+impl EnvFile {
+    // Read and check an environment file, resolving local paths against its folder
+    pub fn load(path: &Path) -> Result<EnvFile> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        let mut env: EnvFile =
+            toml::from_str(&contents).with_context(|| format!("Failed to parse {:?}", path))?;
+
+        let base_dir = path.parent().unwrap_or(Path::new("."));
+        for (index, package) in env.packages.iter_mut().enumerate() {
+            package
+                .validate()
+                .with_context(|| format!("Invalid package #{} in {:?}", index + 1, path))?;
+            if let Some(local) = &package.path {
+                package.path = Some(base_dir.join(local));
+            }
+        }
+        Ok(env)
+    }
+}
+
+impl EnvPackage {
+    // Check that the entry names exactly one source and at most one ref
+    fn validate(&self) -> Result<()> {
+        match (&self.git, &self.path) {
+            (Some(_), Some(_)) => bail!("Give either `git` or `path`, not both"),
+            (None, None) => bail!("Give a `git` URL or a local `path`"),
+            _ => {}
+        }
+        let refs = [&self.branch, &self.tag, &self.rev]
+            .iter()
+            .filter(|git_ref| git_ref.is_some())
+            .count();
+        if refs > 1 {
+            bail!("Give at most one of `branch`, `tag` and `rev`");
+        }
+        if (refs > 0 || self.depth.is_some()) && self.path.is_some() {
+            bail!("`branch`, `tag`, `rev` and `depth` only apply to git packages");
+        }
+        Ok(())
+    }
+
+    // The source as passed to `ipac install`
+    pub fn install_source(&self) -> String {
+        match (&self.git, &self.path) {
+            (Some(git), _) => git.clone(),
+            (None, Some(path)) => path.to_string_lossy().into_owned(),
+            (None, None) => unreachable!("validated on load"),
+        }
+    }
+
+    // The source as recorded in the registry: the URL without `#ref` or `@version`, or the canonical path
+    pub fn registry_source(&self) -> String {
+        match (&self.git, &self.path) {
+            (Some(git), _) => {
+                let url = split_url_ref(git).0;
+                let url = split_url_version(url).map_or(url, |(url, _)| url);
+                GitSource::parse(url).map_or_else(|| url.to_string(), |source| source.url)
+            }
+            (None, Some(path)) => path
+                .canonicalize()
+                .unwrap_or_else(|_| path.clone())
+                .to_string_lossy()
+                .into_owned(),
+            (None, None) => unreachable!("validated on load"),
+        }
+    }
+
+    // The explicitly requested ref, if any
+    pub fn git_ref(&self) -> Option<GitRef> {
+        if let Some(branch) = &self.branch {
+            Some(GitRef::Branch(branch.clone()))
+        } else if let Some(tag) = &self.tag {
+            Some(GitRef::Tag(tag.clone()))
+        } else {
+            self.rev.clone().map(GitRef::Rev)
+        }
+    }
+
+    // Name of the requested ref, whether given as an option or after '#'
+    pub fn requested_ref_name(&self) -> Option<String> {
+        match self.git_ref() {
+            Some(git_ref) => Some(git_ref.name().to_string()),
+            None => split_url_ref(self.git.as_deref()?).1.map(str::to_string),
+        }
+    }
+
+    // Semver requirement given after '@' in the git URL, if any
+    pub fn version_req(&self) -> Option<VersionReq> {
+        let url = split_url_ref(self.git.as_deref()?).0;
+        split_url_version(url).ok()?.1
+    }
+
+    // How much of the repository to check out
+    pub fn clone_options(&self) -> CloneOptions {
+        CloneOptions {
+            depth: self.depth,
+            subdir: self.subdir.clone(),
+            skip_submodules: self.submodules == Some(false),
+        }
+    }
+
+    // Which Igor Pro versions to install into
+    pub fn selection(&self) -> VersionSelection {
+        if self.igor_versions.is_empty() {
+            VersionSelection::Highest
+        } else if self.igor_versions.iter().any(|version| version == "all") {
+            VersionSelection::All
+        } else {
+            VersionSelection::Requested(self.igor_versions.clone())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn package(git: &str) -> EnvPackage {
+        let env: EnvFile = toml::from_str(&format!("[[package]]\ngit = {:?}\n", git)).unwrap();
+        env.packages.into_iter().next().unwrap()
+    }
+
+    #[test]
+    fn reads_the_version_requirement_from_the_url() {
+        let repo = package("file:///srv/repo@^2");
+        assert_eq!(repo.version_req(), Some(VersionReq::parse("^2").unwrap()));
+        assert_eq!(repo.registry_source(), "file:///srv/repo");
+
+        assert_eq!(package("gh:o/r").version_req(), None);
+        assert_eq!(package("gh:o/r#v1.0").version_req(), None);
+    }
+}
@@ -0,0 +1,899 @@
+use crate::auth::with_credentials;
+use crate::config::igor_home;
+use crate::progress::{message, Transfer};
+use anyhow::{bail, Context, Result};
+use git2::build::{CheckoutBuilder, RepoBuilder};
+use git2::{BranchType, ConfigLevel, Oid, Repository, SubmoduleUpdateOptions};
+use semver::{Version, VersionReq};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+// A branch, tag or commit to check out instead of the remote's default branch
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum GitRef {
+    Branch(String),
+    Tag(String),
+    Rev(String),
+}
+
+impl GitRef {
+    // The branch name, tag name or revision
+    pub fn name(&self) -> &str {
+        match self {
+            GitRef::Branch(name) | GitRef::Tag(name) | GitRef::Rev(name) => name,
+        }
+    }
+
+    // Tags and commits never move, so updates leave them where they are
+    pub fn is_pinned(&self) -> bool {
+        !matches!(self, GitRef::Branch(_))
+    }
+}
+
+impl fmt::Display for GitRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            GitRef::Branch(name) => write!(f, "branch {}", name),
+            GitRef::Tag(name) => write!(f, "tag {}", name),
+            GitRef::Rev(rev) => write!(f, "rev {:.12}", rev),
+        }
+    }
+}
+
+// A ref requested on the command line; `url#name` does not say which kind it is,
+// and `url@^1.2` asks for the highest tag satisfying a semver requirement
+pub enum RefRequest {
+    Exact(GitRef),
+    Named(String),
+    Version(VersionReq),
+}
+
+// Outcome of fetching a package clone
+pub enum UpdateResult {
+    UpToDate(Oid),
+    FastForwarded { from: Oid, to: Oid, commits: usize },
+}
+
+// Split "url#ref" into the URL and the ref named after the '#'
+pub fn split_url_ref(url: &str) -> (&str, Option<&str>) {
+    match url.rsplit_once('#') {
+        Some((url, name)) if !name.is_empty() => (url, Some(name)),
+        _ => (url, None),
+    }
+}
+
+// Split "url@^1.2" into the URL and the version requirement after the '@'
+//
+// An '@' followed by a host or path, as in `git@github.com:org/repo.git`, is part
+// of the URL.
+pub fn split_url_version(url: &str) -> Result<(&str, Option<VersionReq>)> {
+    match url.rsplit_once('@') {
+        Some((base, req)) if !base.is_empty() && !req.contains([':', '/']) => {
+            let req = VersionReq::parse(req)
+                .with_context(|| format!("Invalid version requirement {:?}", req))?;
+            Ok((base, Some(req)))
+        }
+        _ => Ok((url, None)),
+    }
+}
+
+// A git remote, as given on the command line or in a manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GitSource {
+    // URL handed to git, with `gh:` shorthand expanded
+    pub url: String,
+    // Repository name without any `.git` suffix, used for the clone folder
+    pub name: String,
+}
+
+// URL schemes git can clone from
+const GIT_SCHEMES: [&str; 6] = [
+    "https://",
+    "http://",
+    "ssh://",
+    "git://",
+    "git+ssh://",
+    "file://",
+];
+
+impl GitSource {
+    // Recognise https, ssh, git and file URLs, scp-like `user@host:path` remotes and
+    // `gh:owner/repo` shorthand; anything else is a local folder
+    pub fn parse(source: &str) -> Option<GitSource> {
+        let url = if let Some(repo) = source.strip_prefix("gh:") {
+            let repo = repo.trim_end_matches('/');
+            if repo.split('/').filter(|part| !part.is_empty()).count() != 2 {
+                return None;
+            }
+            format!("https://github.com/{}.git", repo.trim_end_matches(".git"))
+        } else if GIT_SCHEMES.iter().any(|scheme| source.starts_with(scheme)) || is_scp_like(source)
+        {
+            source.to_string()
+        } else {
+            return None;
+        };
+
+        let path = url.trim_end_matches('/');
+        let last = path.rsplit(['/', ':']).next().unwrap_or(path);
+        let name = last.strip_suffix(".git").unwrap_or(last);
+        let name = if name.is_empty() { "repository" } else { name };
+        Some(GitSource {
+            name: name.to_string(),
+            url,
+        })
+    }
+}
+
+// `user@host:path`, as used by ssh remotes; a '/' before the ':' means a local path
+fn is_scp_like(source: &str) -> bool {
+    match source.split_once(':') {
+        Some((host, path)) => {
+            host.contains('@') && !host.contains('/') && !host.contains('\\') && !path.is_empty()
+        }
+        None => false,
+    }
+}
+
+// How much of a repository to materialize under ~/.igor
+#[derive(Debug, Clone, Default)]
+pub struct CloneOptions {
+    // Fetch only this many commits of history
+    pub depth: Option<u32>,
+    // Package folder inside the repository; a new clone checks out only this folder
+    pub subdir: Option<PathBuf>,
+    // Leave git submodules uninitialized
+    pub skip_submodules: bool,
+}
+
+// Clone settings remembered in the clone's own git config, so later fetches and
+// checkouts keep the clone as shallow and sparse as it started
+const DEPTH_KEY: &str = "ipac.depth";
+const SPARSE_KEY: &str = "ipac.sparsepath";
+const SUBMODULES_KEY: &str = "ipac.submodules";
+
+// Folder that stands in for ~/.igor during a dry run
+static SCRATCH_DIR: OnceLock<PathBuf> = OnceLock::new();
+
+// A temporary folder that every clone and fetch of a dry run goes to, so ~/.igor is
+// left untouched; it is deleted when dropped
+pub struct ScratchClones {
+    dir: PathBuf,
+}
+
+impl ScratchClones {
+    // Send the clones and fetches of the rest of the run to a temporary folder
+    pub fn start() -> Result<ScratchClones> {
+        let dir = std::env::temp_dir().join(format!("ipac-dry-run-{}", std::process::id()));
+        fs::create_dir_all(&dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        let dir = dir.canonicalize()?;
+        let _ = SCRATCH_DIR.set(dir.clone());
+        Ok(ScratchClones { dir })
+    }
+}
+
+impl Drop for ScratchClones {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Folder clones are made in: ~/.igor, or the scratch folder during a dry run
+fn clones_dir() -> PathBuf {
+    SCRATCH_DIR.get().cloned().unwrap_or_else(igor_home)
+}
+
+// Path under ~/.igor that a path in the scratch folder stands for
+pub fn real_clone_path(path: &Path) -> PathBuf {
+    match SCRATCH_DIR
+        .get()
+        .and_then(|dir| path.strip_prefix(dir).ok())
+    {
+        Some(relative) => {
+            let igor_dir = igor_home();
+            igor_dir.canonicalize().unwrap_or(igor_dir).join(relative)
+        }
+        None => path.to_path_buf(),
+    }
+}
+
+// Clone to fetch into: `repo_dir` itself, or a scratch clone standing in for it
+// during a dry run
+pub fn working_clone(repo_dir: &Path) -> Result<PathBuf> {
+    let Some(scratch) = SCRATCH_DIR.get() else {
+        return Ok(repo_dir.to_path_buf());
+    };
+    let copy = scratch.join(repo_dir.file_name().unwrap_or_default());
+    if !copy.exists() {
+        scratch_clone(repo_dir, &copy, None)?;
+    }
+    Ok(copy)
+}
+
+// Set up a clone that stands in for an existing one, with the same refs, origin
+// and ipac settings, checked out where the existing one is or at `at`
+//
+// The scratch clone reads the existing clone's objects through git's alternates
+// instead of copying them, so only the checked out files are written. Whatever a
+// dry run fetches goes to the scratch clone and the existing one is never changed.
+fn scratch_clone(from: &Path, to: &Path, at: Option<Oid>) -> Result<()> {
+    let original =
+        Repository::open(from).with_context(|| format!("{:?} is not a git repository", from))?;
+    let copy = Repository::init(to)
+        .with_context(|| format!("Failed to set up a scratch clone at {:?}", to))?;
+
+    let info = copy.path().join("objects").join("info");
+    fs::create_dir_all(&info)?;
+    let objects = original.path().join("objects");
+    fs::write(info.join("alternates"), format!("{}\n", objects.display()))?;
+    // A shallow clone's history stops at the commits listed here
+    let shallow = original.path().join("shallow");
+    if shallow.exists() {
+        fs::copy(&shallow, copy.path().join("shallow"))?;
+    }
+
+    for reference in original.references()? {
+        let reference = reference?;
+        let Some(name) = reference.name() else {
+            continue;
+        };
+        if let Some(target) = reference.target() {
+            copy.reference(name, target, true, "ipac dry run")?;
+        } else if let Some(target) = reference.symbolic_target() {
+            copy.reference_symbolic(name, target, true, "ipac dry run")?;
+        }
+    }
+
+    // The origin, branch upstreams and ipac settings
+    let settings = original.config()?.open_level(ConfigLevel::Local)?;
+    let mut config = copy.config()?.open_level(ConfigLevel::Local)?;
+    let mut entries = settings.entries(Some("^(remote|branch|ipac)\\..*"))?;
+    while let Some(entry) = entries.next() {
+        let entry = entry?;
+        if let (Some(name), Some(value)) = (entry.name(), entry.value()) {
+            config.set_multivar(name, "$^", value)?;
+        }
+    }
+
+    let head = original.head()?;
+    match (at, head.name()) {
+        (Some(commit), _) => copy.set_head_detached(commit)?,
+        (None, Some(name)) if head.is_branch() => copy.set_head(name)?,
+        _ => copy.set_head_detached(head.peel_to_commit()?.id())?,
+    }
+    copy.checkout_head(Some(&mut checkout_options(&copy)))?;
+    Ok(())
+}
+
+// A temporary checkout of the commit a clone is about to move to, so the move can be
+// planned and checked before the clone changes; it is deleted when dropped
+pub struct PreviewClone {
+    dir: PathBuf,
+}
+
+impl PreviewClone {
+    // Check out `commit` of the clone at `repo_dir` in a temporary folder
+    pub fn start(repo_dir: &Path, commit: Oid) -> Result<PreviewClone> {
+        let name = repo_dir.file_name().unwrap_or_default().to_string_lossy();
+        let dir = SCRATCH_DIR
+            .get()
+            .cloned()
+            .unwrap_or_else(std::env::temp_dir)
+            .join(format!("ipac-preview-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        let preview = PreviewClone { dir };
+        scratch_clone(repo_dir, &preview.dir, Some(commit))?;
+        let repo = Repository::open(&preview.dir)?;
+        update_submodules(&repo)?;
+        Ok(preview)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Drop for PreviewClone {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+// Clone repository into the user's $HOME/.igor folder and check out the requested ref
+//
+// Returns the clone and the ref it is on, so it can be recorded for later updates.
+pub fn clone_repository_into_igor(
+    source: &GitSource,
+    request: Option<&RefRequest>,
+    options: &CloneOptions,
+) -> Result<(PathBuf, Option<GitRef>)> {
+    // Get the user's home directory and append ".igor"
+    let igor_dir = clones_dir();
+
+    // Ensure the .igor directory exists
+    if !igor_dir.exists() {
+        fs::create_dir_all(&igor_dir)?;
+        message!(
+            "Created .igor directory at: {:?}",
+            real_clone_path(&igor_dir)
+        );
+    }
+
+    // Determine the repo directory under .igor based on repo name
+    let repo_dir = igor_dir.join(&source.name);
+
+    // A dry run starts from a scratch clone of the real clone when there is one
+    let real_dir = igor_home().join(&source.name);
+    if !repo_dir.exists() && repo_dir != real_dir && real_dir.exists() {
+        scratch_clone(&real_dir, &repo_dir, None)?;
+    }
+
+    // Clone the repository into this path
+    let repository = if !repo_dir.exists() {
+        message!("Cloning repository into {:?}", real_clone_path(&repo_dir));
+        let repository = clone_with_progress(source, &repo_dir, options)?;
+        // A shallow clone only has the tips, so fetch the tags and branches a ref may name
+        if options.depth.is_some() && request.is_some() {
+            fetch_all(&repository)?;
+        }
+        repository
+    } else {
+        message!(
+            "Repository already exists at {:?}",
+            real_clone_path(&repo_dir)
+        );
+        let repository = Repository::open(&repo_dir)?;
+        let origin = repository
+            .find_remote("origin")
+            .ok()
+            .and_then(|remote| remote.url().map(str::to_string));
+        if origin.as_deref() != Some(source.url.as_str()) {
+            bail!(
+                "{:?} already holds a clone of {}, not {}",
+                repo_dir,
+                origin.as_deref().unwrap_or("an unknown remote"),
+                source.url
+            );
+        }
+        if request.is_some() {
+            fetch_all(&repository)?;
+        }
+        widen_sparse_checkout(&repository, options.subdir.as_deref())?;
+        repository
+    };
+    if options.skip_submodules {
+        repository.config()?.set_bool(SUBMODULES_KEY, false)?;
+    }
+
+    let git_ref = match request {
+        Some(RefRequest::Exact(git_ref)) => git_ref.clone(),
+        Some(RefRequest::Named(name)) => classify_ref(&repository, name),
+        Some(RefRequest::Version(req)) => {
+            let (tag, version) = matching_tag(&repository, req)?;
+            message!("Resolved {} to {} ({})", req, tag, version);
+            GitRef::Tag(tag)
+        }
+        None => {
+            update_submodules(&repository)?;
+            return Ok((repo_dir, current_branch(&repository)));
+        }
+    };
+    checkout_ref(&repository, &git_ref)?;
+    message!("Checked out {}", git_ref);
+    update_submodules(&repository)?;
+
+    Ok((repo_dir, Some(git_ref)))
+}
+
+// Clone with a progress bar, removing the partial clone if it fails or is interrupted
+fn clone_with_progress(
+    source: &GitSource,
+    repo_dir: &Path,
+    options: &CloneOptions,
+) -> Result<Repository> {
+    let sparse_path = options.subdir.as_deref().map(sparse_path);
+    let transfer = Transfer::start();
+    let cloned = with_credentials(&source.url, &transfer, |mut fetch_options| {
+        if let Some(depth) = options.depth {
+            fetch_options.depth(depth as i32);
+        }
+        let mut checkout = CheckoutBuilder::new();
+        checkout.progress(|_, current, total| transfer.checkout(current, total));
+        if let Some(path) = &sparse_path {
+            checkout.path(path);
+        }
+        let repository = RepoBuilder::new()
+            .fetch_options(fetch_options)
+            .with_checkout(checkout)
+            .clone(&source.url, repo_dir)?;
+
+        let mut config = repository.config()?;
+        if let Some(depth) = options.depth {
+            config.set_i32(DEPTH_KEY, depth as i32)?;
+        }
+        if let Some(path) = &sparse_path {
+            config.set_multivar(SPARSE_KEY, "$^", path)?;
+        }
+        if options.skip_submodules {
+            config.set_bool(SUBMODULES_KEY, false)?;
+        }
+        drop(config);
+        Ok(repository)
+    });
+    let interrupted = transfer.interrupted();
+    drop(transfer);
+
+    // The repository is closed before its folder is removed
+    let err = match cloned {
+        Ok(repository) if !interrupted => return Ok(repository),
+        Ok(repository) => {
+            drop(repository);
+            None
+        }
+        Err(err) => Some(err),
+    };
+    if repo_dir.exists() {
+        fs::remove_dir_all(repo_dir)?;
+    }
+    match err {
+        Some(err) if !interrupted => {
+            Err(err).with_context(|| format!("Failed to clone {}", source.url))
+        }
+        _ => bail!("Interrupted; removed the partial clone at {:?}", repo_dir),
+    }
+}
+
+// Check out more of a sparse clone when another folder of it is installed
+//
+// Installing without a subdirectory turns the clone back into a full checkout.
+fn widen_sparse_checkout(repo: &Repository, subdir: Option<&Path>) -> Result<()> {
+    let paths = sparse_paths(repo);
+    if paths.is_empty() {
+        return Ok(());
+    }
+    let mut config = repo.config()?;
+    match subdir.map(sparse_path) {
+        Some(path) if paths.contains(&path) => return Ok(()),
+        Some(path) => {
+            message!("Adding {} to the sparse checkout", path);
+            config.set_multivar(SPARSE_KEY, "$^", &path)?;
+        }
+        None => {
+            message!("Checking out the whole repository");
+            config.remove_multivar(SPARSE_KEY, ".*")?;
+        }
+    }
+    repo.checkout_head(Some(&mut checkout_options(repo)))?;
+    Ok(())
+}
+
+// Folders a sparse clone checks out; empty for a full clone
+fn sparse_paths(repo: &Repository) -> Vec<String> {
+    let Ok(config) = repo.config() else {
+        return Vec::new();
+    };
+    let Ok(mut entries) = config.multivar(SPARSE_KEY, None) else {
+        return Vec::new();
+    };
+    let mut paths = Vec::new();
+    while let Some(Ok(entry)) = entries.next() {
+        if let Some(value) = entry.value() {
+            paths.push(value.to_string());
+        }
+    }
+    paths
+}
+
+// A subdirectory as a git pathspec, with forward slashes
+fn sparse_path(subdir: &Path) -> String {
+    let parts: Vec<_> = subdir
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect();
+    parts.join("/")
+}
+
+// Forced checkout limited to the folders of a sparse clone
+fn checkout_options(repo: &Repository) -> CheckoutBuilder<'static> {
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    for path in sparse_paths(repo) {
+        checkout.path(path);
+    }
+    checkout
+}
+
+// Initialize and update the submodules of a clone, recursively
+//
+// Submodules outside the folders of a sparse clone are left alone, as are all
+// submodules of a clone installed with `--no-submodules`.
+fn update_submodules(repo: &Repository) -> Result<()> {
+    let enabled = repo
+        .config()
+        .and_then(|config| config.get_bool(SUBMODULES_KEY))
+        .unwrap_or(true);
+    if !enabled {
+        return Ok(());
+    }
+
+    let sparse = sparse_paths(repo);
+    for mut submodule in repo.submodules()? {
+        let path = sparse_path(submodule.path());
+        let wanted = sparse.is_empty()
+            || sparse
+                .iter()
+                .any(|dir| Path::new(&path).starts_with(dir) || Path::new(dir).starts_with(&path));
+        if !wanted {
+            continue;
+        }
+
+        message!("Updating submodule {}", path);
+        let url = submodule.url().unwrap_or(&path).to_string();
+        let transfer = Transfer::start();
+        let updated = with_credentials(&url, &transfer, |fetch_options| {
+            let mut options = SubmoduleUpdateOptions::new();
+            options.fetch(fetch_options);
+            submodule.update(true, Some(&mut options))
+        });
+        if transfer.interrupted() {
+            bail!("Interrupted while updating submodule {}", path);
+        }
+        updated.with_context(|| format!("Failed to update submodule {}", path))?;
+        drop(transfer);
+
+        update_submodules(&submodule.open()?)?;
+    }
+    Ok(())
+}
+
+// Fetch options for a clone, keeping a shallow clone shallow
+fn apply_depth(repo: &Repository, fetch_options: &mut git2::FetchOptions<'_>) {
+    if let Ok(depth) = repo.config().and_then(|config| config.get_i32(DEPTH_KEY)) {
+        fetch_options.depth(depth);
+    }
+}
+
+// Fetch every branch and tag from origin into an existing clone
+fn fetch_all(repo: &Repository) -> Result<()> {
+    let mut remote = repo.find_remote("origin")?;
+    let url = remote.url().unwrap_or("origin").to_string();
+    let transfer = Transfer::start();
+    let fetched = with_credentials(&url, &transfer, |mut fetch_options| {
+        apply_depth(repo, &mut fetch_options);
+        remote.fetch(
+            &[
+                "+refs/heads/*:refs/remotes/origin/*",
+                "+refs/tags/*:refs/tags/*",
+            ],
+            Some(&mut fetch_options),
+            None,
+        )
+    });
+    if transfer.interrupted() {
+        bail!("Interrupted while fetching from origin");
+    }
+    fetched.context("Failed to fetch from origin")?;
+    Ok(())
+}
+
+// Decide whether a `url#name` ref names a branch, a tag or a commit
+fn classify_ref(repo: &Repository, name: &str) -> GitRef {
+    if repo
+        .find_branch(&format!("origin/{}", name), BranchType::Remote)
+        .is_ok()
+    {
+        GitRef::Branch(name.to_string())
+    } else if repo.find_reference(&format!("refs/tags/{}", name)).is_ok() {
+        GitRef::Tag(name.to_string())
+    } else {
+        GitRef::Rev(name.to_string())
+    }
+}
+
+// Highest tag whose name, with an optional leading 'v', satisfies `req`
+fn matching_tag(repo: &Repository, req: &VersionReq) -> Result<(String, Version)> {
+    let tags = repo.tag_names(None)?;
+    tags.iter()
+        .flatten()
+        .filter_map(|tag| {
+            let version = Version::parse(tag.trim_start_matches(['v', 'V'])).ok()?;
+            Some((tag.to_string(), version))
+        })
+        .filter(|(_, version)| req.matches(version))
+        .max_by(|(_, a), (_, b)| a.cmp(b))
+        .with_context(|| format!("No tag satisfies version requirement {}", req))
+}
+
+// Name of the checked out branch, if HEAD is on one
+fn current_branch(repo: &Repository) -> Option<GitRef> {
+    let head = repo.head().ok()?;
+    if !head.is_branch() {
+        return None;
+    }
+    Some(GitRef::Branch(head.shorthand()?.to_string()))
+}
+
+// Check out a branch (tracking origin) or detach HEAD at a tag or commit
+fn checkout_ref(repo: &Repository, git_ref: &GitRef) -> Result<()> {
+    match git_ref {
+        GitRef::Branch(name) => {
+            let upstream = repo
+                .find_branch(&format!("origin/{}", name), BranchType::Remote)
+                .with_context(|| format!("Branch {} does not exist on origin", name))?
+                .get()
+                .peel_to_commit()?;
+            match repo.find_branch(name, BranchType::Local) {
+                // A branch left by an earlier install moves up to what was just fetched
+                Ok(branch) => {
+                    let local = branch.get().peel_to_commit()?.id();
+                    if local != upstream.id() && !repo.graph_descendant_of(local, upstream.id())? {
+                        if !repo.graph_descendant_of(upstream.id(), local)? {
+                            bail!(
+                                "Branch {} in {:?} has diverged from origin and cannot be fast-forwarded",
+                                name,
+                                repo.workdir().unwrap_or(repo.path())
+                            );
+                        }
+                        branch
+                            .into_reference()
+                            .set_target(upstream.id(), "ipac: fast-forward")?;
+                    }
+                }
+                Err(_) => {
+                    let mut branch = repo.branch(name, &upstream, false)?;
+                    branch.set_upstream(Some(&format!("origin/{}", name)))?;
+                }
+            }
+            repo.set_head(&format!("refs/heads/{}", name))?;
+        }
+        GitRef::Tag(name) => {
+            let commit = repo
+                .find_reference(&format!("refs/tags/{}", name))
+                .with_context(|| format!("Tag {} does not exist", name))?
+                .peel_to_commit()?;
+            repo.set_head_detached(commit.id())?;
+        }
+        GitRef::Rev(rev) => {
+            let commit = repo
+                .revparse_single(rev)
+                .with_context(|| format!("Revision {} does not exist", rev))?
+                .peel_to_commit()?;
+            repo.set_head_detached(commit.id())?;
+        }
+    }
+    repo.checkout_head(Some(&mut checkout_options(repo)))?;
+    Ok(())
+}
+
+// Put branch `name` at the commit checked out in `repo_dir` and switch to it, so a
+// clone checked out at an exact commit keeps following the branch
+pub fn attach_branch(repo_dir: &Path, name: &str) -> Result<()> {
+    let repo = Repository::open(repo_dir)
+        .with_context(|| format!("{:?} is not a git repository", repo_dir))?;
+    let commit = repo.head()?.peel_to_commit()?;
+    let mut branch = repo.branch(name, &commit, true)?;
+    if repo
+        .find_branch(&format!("origin/{}", name), BranchType::Remote)
+        .is_ok()
+    {
+        branch.set_upstream(Some(&format!("origin/{}", name)))?;
+    }
+    repo.set_head(&format!("refs/heads/{}", name))?;
+    Ok(())
+}
+
+// Fetch a clone's tags and find the highest one satisfying `req`, returning it and
+// its commit when the clone is not there yet
+//
+// The clone itself is left where it is; `move_clone` moves it.
+pub fn fetch_matching_tag(repo_dir: &Path, req: &VersionReq) -> Result<Option<(String, Oid)>> {
+    let repo = Repository::open(repo_dir)
+        .with_context(|| format!("{:?} is not a git repository", repo_dir))?;
+    fetch_all(&repo)?;
+
+    let (tag, _) = matching_tag(&repo, req)?;
+    let target = repo
+        .find_reference(&format!("refs/tags/{}", tag))?
+        .peel_to_commit()?
+        .id();
+    if repo.head()?.peel_to_commit()?.id() == target {
+        update_submodules(&repo)?;
+        return Ok(None);
+    }
+    Ok(Some((tag, target)))
+}
+
+// Move a clone to `commit`, on its checked out branch when it has one and at a
+// detached HEAD otherwise, and update its submodules
+//
+// Rolling back an update moves the clone back the same way.
+pub fn move_clone(repo_dir: &Path, commit: &str) -> Result<()> {
+    let repo = Repository::open(repo_dir)
+        .with_context(|| format!("{:?} is not a git repository", repo_dir))?;
+    let commit = repo
+        .find_commit(Oid::from_str(commit)?)
+        .with_context(|| format!("Commit {} is not in {:?}", commit, repo_dir))?
+        .id();
+    let head = repo.head()?;
+    match head.name() {
+        Some(refname) if head.is_branch() => {
+            let refname = refname.to_string();
+            repo.find_reference(&refname)?
+                .set_target(commit, "ipac update")?;
+            repo.set_head(&refname)?;
+        }
+        _ => repo.set_head_detached(commit)?,
+    }
+    repo.checkout_head(Some(&mut checkout_options(&repo)))?;
+    update_submodules(&repo)
+}
+
+// Commit checked out in a package folder, if it is a git repository
+pub fn head_commit(repo_dir: &Path) -> Option<String> {
+    let repo = Repository::open(repo_dir).ok()?;
+    let commit = repo.head().ok()?.peel_to_commit().ok()?;
+    Some(commit.id().to_string())
+}
+
+// Fetch the upstream of the checked out branch and work out the fast-forward to it
+//
+// Clones under ~/.igor are not meant to be edited, so a branch that has diverged
+// from its upstream is reported rather than merged. The clone itself is left where
+// it is; `move_clone` moves it.
+pub fn fetch_fast_forward(repo_dir: &Path) -> Result<UpdateResult> {
+    let repo = Repository::open(repo_dir)
+        .with_context(|| format!("{:?} is not a git repository", repo_dir))?;
+
+    let head = repo.head()?;
+    if !head.is_branch() {
+        bail!(
+            "{:?} has a detached HEAD; check out a branch to update it",
+            repo_dir
+        );
+    }
+    let branch_name = head.shorthand().unwrap_or("HEAD").to_string();
+    let local = head.peel_to_commit()?.id();
+
+    let mut remote = repo.find_remote("origin")?;
+    let url = remote.url().unwrap_or("origin").to_string();
+    let transfer = Transfer::start();
+    let fetched = with_credentials(&url, &transfer, |mut fetch_options| {
+        apply_depth(&repo, &mut fetch_options);
+        remote.fetch(&[&branch_name], Some(&mut fetch_options), None)
+    });
+    if transfer.interrupted() {
+        bail!("Interrupted while fetching {} from origin", branch_name);
+    }
+    fetched.with_context(|| format!("Failed to fetch {} from origin", branch_name))?;
+    drop(transfer);
+
+    let fetch_head = repo.find_reference("FETCH_HEAD")?;
+    let fetched = repo.reference_to_annotated_commit(&fetch_head)?;
+    let (analysis, _) = repo.merge_analysis(&[&fetched])?;
+
+    if analysis.is_up_to_date() {
+        update_submodules(&repo)?;
+        return Ok(UpdateResult::UpToDate(local));
+    }
+    if !analysis.is_fast_forward() {
+        bail!(
+            "Branch {} in {:?} has diverged from origin and cannot be fast-forwarded",
+            branch_name,
+            repo_dir
+        );
+    }
+
+    let upstream = fetched.id();
+    let mut revwalk = repo.revwalk()?;
+    revwalk.push(upstream)?;
+    revwalk.hide(local)?;
+    let commits = revwalk.count();
+
+    Ok(UpdateResult::FastForwarded {
+        from: local,
+        to: upstream,
+        commits,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(source: &str) -> Option<(String, String)> {
+        GitSource::parse(source).map(|source| (source.url, source.name))
+    }
+
+    #[test]
+    fn parses_urls() {
+        assert_eq!(
+            parse("https://github.com/org/repo.git"),
+            Some(("https://github.com/org/repo.git".into(), "repo".into()))
+        );
+        assert_eq!(
+            parse("ssh://git@host.org/org/repo/"),
+            Some(("ssh://git@host.org/org/repo/".into(), "repo".into()))
+        );
+        assert_eq!(
+            parse("file:///srv/git/analysis.git"),
+            Some(("file:///srv/git/analysis.git".into(), "analysis".into()))
+        );
+    }
+
+    #[test]
+    fn parses_scp_like_remotes() {
+        assert_eq!(
+            parse("git@github.com:org/repo.git"),
+            Some(("git@github.com:org/repo.git".into(), "repo".into()))
+        );
+        assert_eq!(
+            parse("me@beamline:repo"),
+            Some(("me@beamline:repo".into(), "repo".into()))
+        );
+    }
+
+    #[test]
+    fn expands_github_shorthand() {
+        assert_eq!(
+            parse("gh:owner/repo"),
+            Some(("https://github.com/owner/repo.git".into(), "repo".into()))
+        );
+        assert_eq!(
+            parse("gh:owner/repo.git/"),
+            Some(("https://github.com/owner/repo.git".into(), "repo".into()))
+        );
+        assert_eq!(parse("gh:owner"), None);
+        assert_eq!(parse("gh:owner/repo/extra"), None);
+    }
+
+    #[test]
+    fn local_folders_are_not_git_sources() {
+        assert_eq!(parse("github-stuff"), None);
+        assert_eq!(parse("git-packages/analysis"), None);
+        assert_eq!(parse("http-tools"), None);
+        assert_eq!(parse("./me@host:dir"), None);
+        assert_eq!(parse("C:\\Users\\me@lab:x"), None);
+        assert_eq!(parse("C:/Users/me/pkg"), None);
+    }
+
+    #[test]
+    fn scp_like_needs_a_user_host_and_path() {
+        assert!(is_scp_like("git@github.com:org/repo.git"));
+        assert!(!is_scp_like("github.com:org/repo.git"));
+        assert!(!is_scp_like("git@github.com:"));
+        assert!(!is_scp_like("dir/me@host:path"));
+    }
+
+    #[test]
+    fn splits_version_requirements() {
+        let (url, req) = split_url_version("https://github.com/org/repo@^1.2").unwrap();
+        assert_eq!(url, "https://github.com/org/repo");
+        assert_eq!(req, Some(VersionReq::parse("^1.2").unwrap()));
+
+        let (url, req) = split_url_version("git@github.com:org/repo.git").unwrap();
+        assert_eq!(url, "git@github.com:org/repo.git");
+        assert_eq!(req, None);
+
+        let (url, req) = split_url_version("gh:org/repo@~2").unwrap();
+        assert_eq!(url, "gh:org/repo");
+        assert_eq!(req, Some(VersionReq::parse("~2").unwrap()));
+
+        assert!(split_url_version("https://github.com/org/repo@not a version").is_err());
+    }
+
+    #[test]
+    fn splits_refs() {
+        assert_eq!(
+            split_url_ref("https://github.com/org/repo#v1.0"),
+            ("https://github.com/org/repo", Some("v1.0"))
+        );
+        assert_eq!(
+            split_url_ref("https://github.com/org/repo#"),
+            ("https://github.com/org/repo#", None)
+        );
+        assert_eq!(split_url_ref("gh:org/repo"), ("gh:org/repo", None));
+    }
+}
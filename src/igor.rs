@@ -0,0 +1,469 @@
+use crate::config::Config;
+use crate::wine;
+use anyhow::{bail, Result};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Default location of Igor Pro installations on Windows
+pub const IGOR_INSTALL_DIR: &str = "C:/Program Files/WaveMetrics";
+
+// Default location of Igor Pro installations on macOS
+pub const MACOS_INSTALL_DIR: &str = "/Applications";
+
+// A place to look for Igor Pro: an install root and its matching User Files root
+#[derive(Debug, Clone)]
+pub struct IgorRoot {
+    // Short description shown in listings, e.g. "native" or "wine ~/.wine"
+    pub label: String,
+    // Folder containing the "Igor Pro N Folder" installations
+    pub igor_dir: PathBuf,
+    // Folder containing the "Igor Pro N User Files" folders
+    pub user_files_dir: PathBuf,
+}
+
+// An Igor Pro installation found under one of the discovery roots
+#[derive(Debug, Clone)]
+pub struct IgorInstall {
+    pub version: IgorVersion,
+    pub root: String,
+    // The "Igor Pro N User Files" folder for this installation
+    pub user_files: PathBuf,
+}
+
+impl IgorInstall {
+    // Get WaveMetrics path based on folder type (User Procedures or Igor Procedures)
+    pub fn wave_metrics_path<P: AsRef<Path>>(&self, folder_type: P) -> PathBuf {
+        self.user_files.join(folder_type)
+    }
+}
+
+impl fmt::Display for IgorInstall {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.version)?;
+        if self.root != NATIVE_ROOT {
+            write!(f, " [{}]", self.root)?;
+        }
+        Ok(())
+    }
+}
+
+const NATIVE_ROOT: &str = "native";
+
+// Every root searched for Igor Pro installations
+pub struct IgorPaths {
+    pub roots: Vec<IgorRoot>,
+}
+
+impl IgorPaths {
+    // Combine command line or environment overrides with the config file and defaults
+    //
+    // The native roots are always searched on Windows and macOS, and elsewhere only
+    // when configured explicitly. Wine prefixes listed by the user are always searched;
+    // WINEPREFIX and ~/.wine are only picked up when the native root was not overridden.
+    pub fn resolve(
+        igor_dir: Option<PathBuf>,
+        user_files_dir: Option<PathBuf>,
+        wine_prefixes: Vec<PathBuf>,
+        config: &Config,
+    ) -> IgorPaths {
+        let igor_dir = igor_dir.or_else(|| config.igor_dir.clone());
+        let user_files_dir = user_files_dir.or_else(|| config.user_files_dir.clone());
+        let overridden = igor_dir.is_some() || user_files_dir.is_some();
+
+        let mut roots = Vec::new();
+        let default_dirs = default_igor_dirs();
+        if overridden || !default_dirs.is_empty() {
+            let igor_dirs = match igor_dir {
+                Some(igor_dir) => vec![igor_dir],
+                None if default_dirs.is_empty() => vec![PathBuf::from(IGOR_INSTALL_DIR)],
+                None => default_dirs,
+            };
+            let user_files_dir = user_files_dir.unwrap_or_else(default_user_files_dir);
+            for igor_dir in igor_dirs {
+                roots.push(IgorRoot {
+                    label: NATIVE_ROOT.to_string(),
+                    igor_dir,
+                    user_files_dir: user_files_dir.clone(),
+                });
+            }
+        }
+
+        if !cfg!(windows) {
+            let mut explicit = wine_prefixes;
+            explicit.extend(config.wine_prefixes.iter().cloned());
+            for prefix in wine::wine_prefixes(explicit, !overridden) {
+                if let Some(root) = wine::wine_root(&prefix) {
+                    roots.push(root);
+                }
+            }
+        }
+
+        IgorPaths { roots }
+    }
+
+    // List every Igor Pro installation under every root, highest version first
+    pub fn find_installs(&self) -> Result<Vec<IgorInstall>> {
+        let mut installs = Vec::new();
+        for root in &self.roots {
+            for version in find_igor_versions(&root.igor_dir)? {
+                installs.push(IgorInstall {
+                    user_files: root.user_files_dir.join(version.user_files_dir_name()),
+                    root: root.label.clone(),
+                    version,
+                });
+            }
+        }
+
+        installs.sort_by(|a, b| b.version.cmp(&a.version));
+        Ok(installs)
+    }
+
+    // Find the Igor Pro installations selected on the command line
+    pub fn find_selected_installs(&self, selection: &VersionSelection) -> Result<Vec<IgorInstall>> {
+        let installs = self.find_installs()?;
+        if installs.is_empty() {
+            let searched: Vec<_> = self.roots.iter().map(|root| &root.igor_dir).collect();
+            bail!("No Igor Pro installations found in {:?}", searched);
+        }
+        select_igor_installs(installs, selection)
+    }
+}
+
+// Folders that hold "Igor Pro N Folder" installations on this platform
+fn default_igor_dirs() -> Vec<PathBuf> {
+    if cfg!(windows) {
+        vec![PathBuf::from(IGOR_INSTALL_DIR)]
+    } else if cfg!(target_os = "macos") {
+        // The installer targets /Applications, but users without admin rights
+        // often drag the folder into ~/Applications instead
+        let mut igor_dirs = vec![PathBuf::from(MACOS_INSTALL_DIR)];
+        if let Some(home) = dirs::home_dir() {
+            igor_dirs.push(home.join("Applications"));
+        }
+        igor_dirs
+    } else {
+        Vec::new()
+    }
+}
+
+// Documents/WaveMetrics, falling back to ~/Documents when the platform does not report one
+fn default_user_files_dir() -> PathBuf {
+    let doc_dir = dirs::document_dir()
+        .or_else(|| dirs::home_dir().map(|home| home.join("Documents")))
+        .expect("Could not locate the user's Documents folder.");
+    doc_dir.join("WaveMetrics")
+}
+
+// Processor architecture of an Igor Pro installation
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Arch {
+    X86,
+    X64,
+}
+
+// An Igor Pro installation parsed from its folder name under WaveMetrics
+//
+// Fields are ordered so the derived ordering compares major, then minor, then
+// prefers 64-bit builds over 32-bit ones.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct IgorVersion {
+    pub major: u32,
+    pub minor: u32,
+    pub arch: Arch,
+    pub folder: String,
+}
+
+impl IgorVersion {
+    // Parse a WaveMetrics folder name such as "Igor Pro 9 Folder",
+    // "Igor Pro 8.04 Folder (32-bit)" or the unnumbered "Igor Pro Folder" used by Igor 6
+    pub fn parse(folder: &str) -> Option<IgorVersion> {
+        let rest = folder.strip_prefix("Igor Pro")?;
+        let mut number = None;
+        let mut arch = None;
+
+        for token in rest.split_whitespace() {
+            let token = token.trim_matches(|c| c == '(' || c == ')');
+            match token.to_ascii_lowercase().as_str() {
+                "32-bit" | "32bit" | "x86" => arch = Some(Arch::X86),
+                "64-bit" | "64bit" | "x64" => arch = Some(Arch::X64),
+                "folder" | "beta" => {}
+                _ if number.is_none() => number = Some(parse_version(token)?),
+                _ => return None,
+            }
+        }
+
+        // Igor 6 and earlier installed into an unnumbered folder and were 32-bit only
+        let (major, minor) = number.unwrap_or((6, 0));
+        let arch = arch.unwrap_or(if major >= 7 { Arch::X64 } else { Arch::X86 });
+
+        Some(IgorVersion {
+            major,
+            minor,
+            arch,
+            folder: folder.to_string(),
+        })
+    }
+
+    // Name of the per-user folder Igor creates under Documents/WaveMetrics
+    pub fn user_files_dir_name(&self) -> String {
+        format!("Igor Pro {} User Files", self.major)
+    }
+}
+
+impl fmt::Display for IgorVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.major)?;
+        if self.minor != 0 {
+            write!(f, ".{:02}", self.minor)?;
+        }
+        if self.arch == Arch::X86 && self.major >= 7 {
+            write!(f, " (32-bit)")?;
+        }
+        Ok(())
+    }
+}
+
+// Parse "9" or "8.04" into (major, minor), reading a single minor digit as tenths
+pub fn parse_version(token: &str) -> Option<(u32, u32)> {
+    let (major, minor) = match token.split_once('.') {
+        Some((major, minor)) => (major, minor),
+        None => (token, ""),
+    };
+    let major = major.parse().ok()?;
+    let minor = match minor.len() {
+        0 => 0,
+        1 => minor.parse::<u32>().ok()? * 10,
+        _ => minor.parse().ok()?,
+    };
+    Some((major, minor))
+}
+
+// List every Igor Pro installation under `igor_dir`, highest version first
+fn find_igor_versions(igor_dir: &Path) -> Result<Vec<IgorVersion>> {
+    if !igor_dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut versions: Vec<_> = fs::read_dir(igor_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .filter_map(|name| IgorVersion::parse(&name))
+        .collect();
+
+    versions.sort_by(|a, b| b.cmp(a));
+    Ok(versions)
+}
+
+// Which installed Igor Pro versions a command should act on
+pub enum VersionSelection {
+    Highest,
+    Requested(Vec<String>),
+    All,
+}
+
+// Check whether a requested version such as "8" or "8.04" names this installation
+fn matches_request(version: &IgorVersion, request: &str) -> bool {
+    match parse_version(request) {
+        Some((major, minor)) if request.contains('.') => {
+            version.major == major && version.minor == minor
+        }
+        Some((major, _)) => version.major == major,
+        None => false,
+    }
+}
+
+// Pick the installations to install into, keeping one per User Files tree
+pub fn select_igor_installs(
+    installs: Vec<IgorInstall>,
+    selection: &VersionSelection,
+) -> Result<Vec<IgorInstall>> {
+    let mut selected: Vec<IgorInstall> = match selection {
+        VersionSelection::Highest => installs.into_iter().take(1).collect(),
+        VersionSelection::All => installs,
+        VersionSelection::Requested(requests) => {
+            let mut selected = Vec::new();
+            for request in requests {
+                let matching: Vec<_> = installs
+                    .iter()
+                    .filter(|install| matches_request(&install.version, request))
+                    .cloned()
+                    .collect();
+                if matching.is_empty() {
+                    bail!("Igor Pro {} is not installed", request);
+                }
+                selected.extend(matching);
+            }
+            selected
+        }
+    };
+
+    // 32-bit and 64-bit builds of the same major version share one User Files folder
+    selected.sort_by(|a, b| b.version.cmp(&a.version));
+    let mut seen = Vec::new();
+    selected.retain(|install| {
+        if seen.contains(&install.user_files) {
+            return false;
+        }
+        seen.push(install.user_files.clone());
+        true
+    });
+
+    if selected.is_empty() {
+        bail!("No Igor Pro installations found.");
+    }
+    Ok(selected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn version(folder: &str) -> IgorVersion {
+        IgorVersion::parse(folder).expect("folder name should parse")
+    }
+
+    fn install(folder: &str, user_files: &str) -> IgorInstall {
+        IgorInstall {
+            version: version(folder),
+            root: NATIVE_ROOT.to_string(),
+            user_files: PathBuf::from(user_files),
+        }
+    }
+
+    // Empty folder under the system temp folder, unique to one test
+    fn scratch_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ipac-test-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn parses_folder_names() {
+        let igor9 = version("Igor Pro 9 Folder");
+        assert_eq!((igor9.major, igor9.minor, igor9.arch), (9, 0, Arch::X64));
+
+        let igor8 = version("Igor Pro 8.04 Folder (32-bit)");
+        assert_eq!((igor8.major, igor8.minor, igor8.arch), (8, 4, Arch::X86));
+
+        let igor6 = version("Igor Pro Folder");
+        assert_eq!((igor6.major, igor6.minor, igor6.arch), (6, 0, Arch::X86));
+
+        assert!(IgorVersion::parse("Igor Pro 9 Folder backup").is_none());
+        assert!(IgorVersion::parse("Igor Pro Nine Folder").is_none());
+        assert!(IgorVersion::parse("WaveMetrics").is_none());
+    }
+
+    #[test]
+    fn parses_version_numbers() {
+        assert_eq!(parse_version("9"), Some((9, 0)));
+        assert_eq!(parse_version("8.04"), Some((8, 4)));
+        assert_eq!(parse_version("8.5"), Some((8, 50)));
+        assert_eq!(parse_version("x"), None);
+    }
+
+    #[test]
+    fn igor_10_sorts_above_9() {
+        let mut versions = [
+            version("Igor Pro 9 Folder"),
+            version("Igor Pro 10 Folder"),
+            version("Igor Pro 8 Folder"),
+        ];
+        versions.sort_by(|a, b| b.cmp(a));
+        let majors: Vec<_> = versions.iter().map(|version| version.major).collect();
+        assert_eq!(majors, [10, 9, 8]);
+    }
+
+    #[test]
+    fn prefers_64_bit_builds() {
+        assert!(version("Igor Pro 9 Folder") > version("Igor Pro 9 Folder (32-bit)"));
+    }
+
+    #[test]
+    fn selects_highest_and_requested_versions() {
+        let installs = vec![
+            install("Igor Pro 10 Folder", "/uf/Igor Pro 10 User Files"),
+            install("Igor Pro 9 Folder", "/uf/Igor Pro 9 User Files"),
+            install("Igor Pro 8.04 Folder", "/uf/Igor Pro 8 User Files"),
+        ];
+
+        let highest = select_igor_installs(installs.clone(), &VersionSelection::Highest).unwrap();
+        assert_eq!(highest.len(), 1);
+        assert_eq!(highest[0].version.major, 10);
+
+        let requested = VersionSelection::Requested(vec!["8.04".to_string(), "9".to_string()]);
+        let selected = select_igor_installs(installs.clone(), &requested).unwrap();
+        let majors: Vec<_> = selected
+            .iter()
+            .map(|install| install.version.major)
+            .collect();
+        assert_eq!(majors, [9, 8]);
+
+        let missing = VersionSelection::Requested(vec!["7".to_string()]);
+        assert!(select_igor_installs(installs, &missing).is_err());
+    }
+
+    #[test]
+    fn keeps_one_install_per_user_files_folder() {
+        let installs = vec![
+            install("Igor Pro 9 Folder", "/uf/Igor Pro 9 User Files"),
+            install("Igor Pro 9 Folder (32-bit)", "/uf/Igor Pro 9 User Files"),
+        ];
+        let selected = select_igor_installs(installs, &VersionSelection::All).unwrap();
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].version.arch, Arch::X64);
+    }
+
+    #[test]
+    fn finds_installs_in_a_folder_tree() {
+        let dir = scratch_dir("find-installs");
+        let igor_dir = dir.join("WaveMetrics");
+        for folder in [
+            "Igor Pro 9 Folder",
+            "Igor Pro 10 Folder",
+            "Igor Pro 8 Folder (32-bit)",
+            "Not Igor",
+        ] {
+            fs::create_dir_all(igor_dir.join(folder)).unwrap();
+        }
+        // Files named like an installation are not installations
+        fs::write(igor_dir.join("Igor Pro 7 Folder"), "").unwrap();
+
+        let paths = IgorPaths {
+            roots: vec![IgorRoot {
+                label: NATIVE_ROOT.to_string(),
+                igor_dir,
+                user_files_dir: dir.join("Documents"),
+            }],
+        };
+        let installs = paths.find_installs().unwrap();
+        let found: Vec<_> = installs
+            .iter()
+            .map(|install| install.version.to_string())
+            .collect();
+        assert_eq!(found, ["10", "9", "8 (32-bit)"]);
+        assert_eq!(
+            installs[0].user_files,
+            dir.join("Documents").join("Igor Pro 10 User Files")
+        );
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn missing_igor_dir_has_no_installs() {
+        let paths = IgorPaths {
+            roots: vec![IgorRoot {
+                label: NATIVE_ROOT.to_string(),
+                igor_dir: PathBuf::from("/nonexistent/WaveMetrics"),
+                user_files_dir: PathBuf::from("/nonexistent/Documents"),
+            }],
+        };
+        assert!(paths.find_installs().unwrap().is_empty());
+        assert!(paths
+            .find_selected_installs(&VersionSelection::Highest)
+            .is_err());
+    }
+}
@@ -0,0 +1,245 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// How package files are placed in the User Files folders
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum InstallMode {
+    // Symbolic links (junctions for folders on Windows) into the package
+    #[default]
+    Symlink,
+    // Independent copies of every file, for machines that cannot create symlinks
+    Copy,
+    // Hard links to every file, which need the package on the same drive
+    Hardlink,
+}
+
+impl InstallMode {
+    // Parse a `--mode` value
+    pub fn parse(mode: &str) -> Option<InstallMode> {
+        match mode {
+            "symlink" => Some(InstallMode::Symlink),
+            "copy" => Some(InstallMode::Copy),
+            "hardlink" => Some(InstallMode::Hardlink),
+            _ => None,
+        }
+    }
+}
+
+// What to do with files already at a destination that ipac did not put there
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    // Report every clash and change nothing
+    #[default]
+    Abort,
+    // Leave the existing file and do not install that one
+    Skip,
+    // Rename the existing file aside, then install
+    Backup,
+    // Delete the existing file, then install
+    Force,
+}
+
+// How package files are written into the User Files folders
+#[derive(Debug, Clone, Copy, Default)]
+pub struct LinkOptions {
+    pub mode: InstallMode,
+    pub on_conflict: ConflictPolicy,
+    // Work out the changes without making them, for `--dry-run`
+    pub dry_run: bool,
+}
+
+// Suffix of the names existing files are renamed to by `--backup`
+const BACKUP_SUFFIX: &str = "ipac-backup";
+
+// Folder in "Igor Pro N User Files" that `--backup` moves files to, outside the
+// folders Igor loads from
+pub const BACKUP_FOLDER: &str = "ipac-backups";
+
+// Copy the file `src` to `dst`
+pub fn copy_file(src: &Path, dst: &Path) -> Result<()> {
+    fs::copy(src, dst).with_context(|| format!("Failed to copy {:?} to {:?}", src, dst))?;
+    Ok(())
+}
+
+// Create a hard link at `dst` to the file `src`
+pub fn hard_link(src: &Path, dst: &Path) -> Result<()> {
+    fs::hard_link(src, dst).with_context(|| {
+        format!(
+            "Failed to hard link {:?} to {:?}. Hard links need both on the same drive; \
+             use --mode copy otherwise",
+            dst, src
+        )
+    })
+}
+
+// SHA-256 of a file's contents, as lowercase hex
+pub fn file_hash(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).with_context(|| format!("Failed to read {:?}", path))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Create a link at `dst` pointing to the file `src`
+#[cfg(unix)]
+pub fn link_file(src: &Path, dst: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+        .with_context(|| format!("Failed to link {:?} to {:?}", dst, src))
+}
+
+// Create a link at `dst` pointing to the directory `src`
+#[cfg(unix)]
+pub fn link_dir(src: &Path, dst: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(src, dst)
+        .with_context(|| format!("Failed to link {:?} to {:?}", dst, src))
+}
+
+// Create a link at `dst` pointing to the file `src`
+#[cfg(windows)]
+pub fn link_file(src: &Path, dst: &Path) -> Result<()> {
+    std::os::windows::fs::symlink_file(src, dst).with_context(|| {
+        format!(
+            "Failed to link {:?} to {:?}. Creating symbolic links on Windows requires \
+             administrator rights or Developer Mode",
+            dst, src
+        )
+    })
+}
+
+// Create a link at `dst` pointing to the directory `src`
+//
+// Directory symlinks need elevated rights on Windows, so fall back to a junction,
+// which any user can create for a local directory.
+#[cfg(windows)]
+pub fn link_dir(src: &Path, dst: &Path) -> Result<()> {
+    if std::os::windows::fs::symlink_dir(src, dst).is_ok() {
+        return Ok(());
+    }
+
+    let status = std::process::Command::new("cmd")
+        .arg("/C")
+        .arg("mklink")
+        .arg("/J")
+        .arg(dst)
+        .arg(src)
+        .stdout(std::process::Stdio::null())
+        .status()
+        .context("Failed to run mklink")?;
+
+    if !status.success() {
+        anyhow::bail!("Failed to create a junction from {:?} to {:?}", dst, src);
+    }
+    Ok(())
+}
+
+// Resolve where the link at `path` points, or None if it is not a link
+pub fn link_target(path: &Path) -> Option<PathBuf> {
+    let meta = fs::symlink_metadata(path).ok()?;
+    if !meta.file_type().is_symlink() {
+        return None;
+    }
+
+    let target = fs::read_link(path).ok()?;
+    let target = match path.parent() {
+        Some(parent) if target.is_relative() => parent.join(target),
+        _ => target,
+    };
+    // Windows reports junction targets with the \\?\ verbatim prefix
+    Some(target.canonicalize().unwrap_or(target))
+}
+
+// Remove the link at `path` without touching what it points to
+#[cfg(unix)]
+pub fn remove_link(path: &Path) -> Result<()> {
+    fs::remove_file(path).with_context(|| format!("Failed to remove link {:?}", path))
+}
+
+// Remove the link at `path` without touching what it points to
+//
+// Directory symlinks and junctions are directories to Windows and must be removed as such.
+#[cfg(windows)]
+pub fn remove_link(path: &Path) -> Result<()> {
+    let result = match fs::metadata(path) {
+        Ok(meta) if meta.is_dir() => fs::remove_dir(path),
+        _ => fs::remove_file(path).or_else(|_| fs::remove_dir(path)),
+    };
+    result.with_context(|| format!("Failed to remove link {:?}", path))
+}
+
+// Delete whatever is at `path`: a link, a file or a whole folder
+pub fn remove_existing(path: &Path) -> Result<()> {
+    let meta =
+        fs::symlink_metadata(path).with_context(|| format!("Failed to inspect {:?}", path))?;
+    if link_target(path).is_some() {
+        remove_link(path)
+    } else if meta.is_dir() {
+        fs::remove_dir_all(path).with_context(|| format!("Failed to remove {:?}", path))
+    } else {
+        fs::remove_file(path).with_context(|| format!("Failed to remove {:?}", path))
+    }
+}
+
+// Give the file at `path` contents of its own, so writing to it no longer changes
+// the files hard linked to it, and the other way round
+pub fn detach_file(path: &Path) -> Result<()> {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let copy = path.with_file_name(format!(".{}.ipac-detach", name));
+    fs::copy(path, &copy).with_context(|| format!("Failed to copy {:?}", path))?;
+    fs::rename(&copy, path).with_context(|| format!("Failed to replace {:?}", path))
+}
+
+// Free path to move `path` to with `--backup`: `<name>.ipac-backup`, numbered when
+// that is taken, at the same place under the `ipac-backups` folder of the User Files
+// folder holding `path`
+//
+// Igor would still load a backup left inside Igor Procedures, so backups only stay
+// next to the original when it is in none of `user_files`.
+pub fn backup_path(path: &Path, user_files: &[PathBuf]) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let moved = user_files
+        .iter()
+        .find_map(|folder| {
+            let relative = path.strip_prefix(folder).ok()?;
+            Some(folder.join(BACKUP_FOLDER).join(relative))
+        })
+        .unwrap_or_else(|| path.to_path_buf());
+    let mut backup = moved.with_file_name(format!("{}.{}", name, BACKUP_SUFFIX));
+    let mut number = 1;
+    while fs::symlink_metadata(&backup).is_ok() {
+        number += 1;
+        backup = moved.with_file_name(format!("{}.{}-{}", name, BACKUP_SUFFIX, number));
+    }
+    backup
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backups_go_outside_the_igor_folders() {
+        let user_files = [PathBuf::from("/uf/Igor Pro 10 User Files")];
+        assert_eq!(
+            backup_path(
+                Path::new("/uf/Igor Pro 10 User Files/Igor Procedures/pkg"),
+                &user_files
+            ),
+            Path::new("/uf/Igor Pro 10 User Files/ipac-backups/Igor Procedures/pkg.ipac-backup")
+        );
+        assert_eq!(
+            backup_path(Path::new("/elsewhere/a.ipf"), &user_files),
+            Path::new("/elsewhere/a.ipf.ipac-backup")
+        );
+    }
+}
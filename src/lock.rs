@@ -0,0 +1,125 @@
+use crate::git::GitRef;
+use crate::registry::{write_atomic, Registry, SourceKind};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Default name of the lockfile written by `ipac lock`
+pub const LOCK_FILE: &str = "ipac.lock";
+
+// Exact package sources and commits for reproducing an Igor environment
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LockFile {
+    #[serde(default, rename = "package")]
+    pub packages: Vec<LockedPackage>,
+}
+
+// A package pinned to the commit it was installed from
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct LockedPackage {
+    pub name: String,
+    pub kind: SourceKind,
+    pub source: String,
+    // Full commit hash; local packages outside git have none
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    // Folder of the source that holds the package
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdir: Option<PathBuf>,
+    // Branch, tag or commit the package followed, restored after checking out `commit`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_ref: Option<GitRef>,
+    // Semver requirement `ipac update` moves the tag along
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_req: Option<String>,
+}
+
+impl LockFile {
+    // Lock every package in the registry at its installed commit
+    pub fn from_registry(registry: &Registry) -> LockFile {
+        let packages = registry
+            .packages
+            .iter()
+            .map(|package| LockedPackage {
+                name: package.name.clone(),
+                kind: package.kind,
+                source: package.source.clone(),
+                commit: package.commit.clone(),
+                subdir: package.subdir.clone(),
+                git_ref: package.git_ref.clone(),
+                version_req: package.version_req.clone(),
+            })
+            .collect();
+        LockFile { packages }
+    }
+
+    // Read a lockfile
+    pub fn load(path: &Path) -> Result<LockFile> {
+        let contents =
+            fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {:?}", path))
+    }
+
+    // Write the lockfile atomically
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let contents = format!(
+            "# Generated by `ipac lock`. Install it with `ipac install --locked`.\n\n{}",
+            toml::to_string_pretty(self)?
+        );
+        write_atomic(path, &contents).with_context(|| format!("Failed to write {:?}", path))
+    }
+
+    // Find the locked entry for a source given on the command line
+    pub fn find_source(&self, source: &str) -> Option<&LockedPackage> {
+        self.packages
+            .iter()
+            .find(|package| package.source == source || package.name == source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_the_followed_ref_and_version_requirement() {
+        let lock: LockFile = toml::from_str(
+            r#"
+            [[package]]
+            name = "utils"
+            kind = "git"
+            source = "https://github.com/o/utils.git"
+            commit = "855b21f0563a"
+            version-req = "^1"
+
+            [package.git-ref]
+            tag = "v1.4.0"
+            "#,
+        )
+        .unwrap();
+        let locked = &lock.packages[0];
+        assert_eq!(locked.git_ref, Some(GitRef::Tag("v1.4.0".to_string())));
+        assert_eq!(locked.version_req.as_deref(), Some("^1"));
+
+        let written: LockFile = toml::from_str(&toml::to_string_pretty(&lock).unwrap()).unwrap();
+        assert_eq!(written.packages[0].git_ref, locked.git_ref);
+        assert_eq!(written.packages[0].version_req, locked.version_req);
+    }
+
+    #[test]
+    fn reads_lockfiles_without_refs() {
+        let lock: LockFile = toml::from_str(
+            r#"
+            [[package]]
+            name = "utils"
+            kind = "path"
+            source = "/data/utils"
+            "#,
+        )
+        .unwrap();
+        assert!(lock.packages[0].git_ref.is_none());
+        assert!(lock.find_source("/data/utils").is_some());
+    }
+}
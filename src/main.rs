@@ -1,11 +1,47 @@
-use anyhow::Result;
-use clap::{Arg, Command};
-use dirs::home_dir;
-use git2::Repository;
-use std::os::windows::fs::{symlink_dir, symlink_file};
+mod auth;
+mod config;
+mod dry_run;
+mod env;
+mod git;
+mod igor;
+mod link;
+mod lock;
+mod manifest;
+mod plan;
+mod progress;
+mod registry;
+mod resolve;
+mod transaction;
+mod wine;
+
+use anyhow::{bail, Result};
+use clap::{Arg, ArgAction, Command};
+use config::{igor_home, Config};
+use dry_run::{print_plan, CloneChange, PackageAction, PlannedPackage};
+use env::{EnvFile, EnvPackage, ENV_FILE};
+use git::{
+    attach_branch, fetch_fast_forward, fetch_matching_tag, head_commit, real_clone_path,
+    split_url_ref, split_url_version, working_clone, CloneOptions, GitRef, PreviewClone,
+    ScratchClones, UpdateResult,
+};
+use igor::{IgorInstall, IgorPaths, VersionSelection};
+use link::{ConflictPolicy, InstallMode, LinkOptions};
+use lock::{LockFile, LockedPackage, LOCK_FILE};
+use manifest::Manifest;
+use plan::{
+    describe_conflict, ensure_no_conflicts, find_conflicts, find_duplicates, plan_links,
+    plan_removal, plan_sync,
+};
+use progress::message;
+use registry::{
+    InstalledLink, InstalledPackage, InstalledTarget, LinkStatus, Registry, SourceKind,
+};
+use resolve::{fetch_package, normalize_source, resolve_dependencies, FetchedPackage};
+use semver::VersionReq;
+use serde::Serialize;
 use std::path::PathBuf;
-use std::process::exit;
 use std::{fs, path::Path};
+use transaction::{Operation, Transaction};
 
 fn main() -> Result<()> {
     // Setup CLI with clap
@@ -13,6 +49,41 @@ fn main() -> Result<()> {
         .version("0.1")
         .author("Your Name <your.email@example.com>")
         .about("Installs Igor Pro procedure files")
+        .arg(
+            Arg::new("igor-dir")
+                .long("igor-dir")
+                .num_args(1)
+                .global(true)
+                .env("IPAC_IGOR_DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Folder containing the Igor Pro installations"),
+        )
+        .arg(
+            Arg::new("user-files-dir")
+                .long("user-files-dir")
+                .num_args(1)
+                .global(true)
+                .env("IPAC_USER_FILES_DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Folder containing the \"Igor Pro N User Files\" folders"),
+        )
+        .arg(
+            Arg::new("wine-prefix")
+                .long("wine-prefix")
+                .num_args(1)
+                .global(true)
+                .action(ArgAction::Append)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Wine prefix to search for Igor Pro (repeatable)"),
+        )
+        .arg(
+            Arg::new("quiet")
+                .short('q')
+                .long("quiet")
+                .global(true)
+                .action(ArgAction::SetTrue)
+                .help("Hide clone and fetch progress bars"),
+        )
         .subcommand(
             Command::new("install")
                 .about("Install procedure files")
@@ -21,7 +92,7 @@ fn main() -> Result<()> {
                         .short('g')
                         .long("git")
                         .num_args(1) // Updated for clap 4.x
-                        .help("GitHub repository to install from"),
+                        .help("Git repository to install from (URL, user@host:path or gh:owner/repo)"),
                 )
                 .arg(
                     Arg::new("path")
@@ -29,151 +100,1469 @@ fn main() -> Result<()> {
                         .long("path")
                         .num_args(1) // Updated for clap 4.x
                         .help("Local directory to install from"),
+                )
+                .arg(
+                    Arg::new("branch")
+                        .long("branch")
+                        .num_args(1)
+                        .requires("git")
+                        .conflicts_with_all(["tag", "rev"])
+                        .help("Branch of the git repository to install"),
+                )
+                .arg(
+                    Arg::new("tag")
+                        .long("tag")
+                        .num_args(1)
+                        .requires("git")
+                        .conflicts_with("rev")
+                        .help("Tag of the git repository to install"),
+                )
+                .arg(
+                    Arg::new("rev")
+                        .long("rev")
+                        .num_args(1)
+                        .requires("git")
+                        .help("Commit of the git repository to install"),
+                )
+                .arg(
+                    Arg::new("depth")
+                        .long("depth")
+                        .num_args(1)
+                        .requires("git")
+                        .value_parser(clap::value_parser!(u32).range(1..))
+                        .help("Fetch only this many commits of history"),
+                )
+                .arg(
+                    Arg::new("subdir")
+                        .long("subdir")
+                        .num_args(1)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Install the package in this folder of the repository or path"),
+                )
+                .arg(
+                    Arg::new("no-submodules")
+                        .long("no-submodules")
+                        .requires("git")
+                        .action(ArgAction::SetTrue)
+                        .help("Leave git submodules uninitialized, now and on update"),
+                )
+                .arg(
+                    Arg::new("mode")
+                        .long("mode")
+                        .num_args(1)
+                        .value_parser(["symlink", "copy", "hardlink"])
+                        .help("Link, copy or hard link the package files [default: symlink]"),
+                )
+                .args(conflict_args())
+                .args(dry_run_args())
+                .arg(
+                    Arg::new("locked")
+                        .long("locked")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with_all(["branch", "tag", "rev", "subdir", "depth", "no-submodules"])
+                        .help("Install the exact commits in the lockfile, or everything in it when no source is given"),
+                )
+                .arg(
+                    Arg::new("lockfile")
+                        .long("lockfile")
+                        .num_args(1)
+                        .requires("locked")
+                        .default_value(LOCK_FILE)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Lockfile to read with --locked"),
+                )
+                .arg(
+                    Arg::new("igor-version")
+                        .short('v')
+                        .long("igor-version")
+                        .num_args(1)
+                        .action(ArgAction::Append)
+                        .conflicts_with("all-versions")
+                        .help("Igor Pro version to install into, e.g. 8 or 9.05 (repeatable)"),
+                )
+                .arg(
+                    Arg::new("all-versions")
+                        .short('a')
+                        .long("all-versions")
+                        .action(ArgAction::SetTrue)
+                        .help("Install into every installed Igor Pro version"),
+                ),
+        )
+        .subcommand(
+            Command::new("uninstall")
+                .about("Remove the links a package installed")
+                .arg(
+                    Arg::new("package")
+                        .required(true)
+                        .help("Name of a package under ~/.igor or path to a local package"),
+                )
+                .arg(
+                    Arg::new("remove-clone")
+                        .long("remove-clone")
+                        .action(ArgAction::SetTrue)
+                        .help("Also delete the package's clone under ~/.igor"),
+                )
+                .args(dry_run_args()),
+        )
+        .subcommand(
+            Command::new("update")
+                .about("Fetch new commits for installed packages and re-sync their links")
+                .arg(
+                    Arg::new("package")
+                        .num_args(1..)
+                        .required_unless_present("all")
+                        .help("Names of the packages to update"),
+                )
+                .arg(
+                    Arg::new("all")
+                        .long("all")
+                        .action(ArgAction::SetTrue)
+                        .conflicts_with("package")
+                        .help("Update every installed package"),
+                )
+                .args(conflict_args())
+                .args(dry_run_args()),
+        )
+        .subcommand(
+            Command::new("sync")
+                .about("Make the installed packages match an environment file")
+                .arg(
+                    Arg::new("file")
+                        .short('f')
+                        .long("file")
+                        .num_args(1)
+                        .default_value(ENV_FILE)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Environment file listing the packages to install"),
+                )
+                .args(conflict_args())
+                .args(dry_run_args()),
+        )
+        .subcommand(
+            Command::new("lock")
+                .about("Write the installed packages and their commits to a lockfile")
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .num_args(1)
+                        .default_value(LOCK_FILE)
+                        .value_parser(clap::value_parser!(PathBuf))
+                        .help("Where to write the lockfile"),
                 ),
         )
+        .subcommand(
+            Command::new("list")
+                .about("List installed packages and the state of their links")
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print the list as JSON"),
+                ),
+        )
+        .subcommand(Command::new("versions").about("List installed Igor Pro versions"))
         .get_matches();
 
+    progress::set_quiet(matches.get_flag("quiet"));
+
+    // Resolve the Igor Pro roots shared by every subcommand
+    let config = Config::load()?;
+    let paths = IgorPaths::resolve(
+        matches.get_one::<PathBuf>("igor-dir").cloned(),
+        matches.get_one::<PathBuf>("user-files-dir").cloned(),
+        matches
+            .get_many::<PathBuf>("wine-prefix")
+            .map(|prefixes| prefixes.cloned().collect())
+            .unwrap_or_default(),
+        &config,
+    );
+
+    // A dry run clones and fetches into a scratch folder and changes nothing else
+    let (dry_run, json) = matches
+        .subcommand()
+        .map_or((false, false), |(_, matches)| dry_run_flags(matches));
+    let _scratch = if dry_run {
+        progress::set_messages_to_stderr(json);
+        Some(ScratchClones::start()?)
+    } else {
+        None
+    };
+
+    // Undo whatever an interrupted install, update or uninstall left half done
+    if !dry_run
+        && matches!(
+            matches.subcommand_name(),
+            Some("install" | "uninstall" | "update" | "sync")
+        )
+    {
+        transaction::recover()?;
+    }
+
     // Handle the 'install' command
     if let Some(matches) = matches.subcommand_matches("install") {
         let repo_path = matches
             .get_one::<String>("git")
             .or(matches.get_one::<String>("path"));
 
-        if let Some(repo_path) = repo_path {
-            install_procedure_files(repo_path)?;
+        let selection = if matches.get_flag("all-versions") {
+            VersionSelection::All
+        } else if let Some(versions) = matches.get_many::<String>("igor-version") {
+            VersionSelection::Requested(versions.cloned().collect())
+        } else {
+            VersionSelection::Highest
+        };
+
+        let git_ref = if let Some(branch) = matches.get_one::<String>("branch") {
+            Some(GitRef::Branch(branch.clone()))
+        } else if let Some(tag) = matches.get_one::<String>("tag") {
+            Some(GitRef::Tag(tag.clone()))
         } else {
-            println!("Please provide a valid path or GitHub repository");
-            exit(1);
+            matches
+                .get_one::<String>("rev")
+                .map(|rev| GitRef::Rev(rev.clone()))
+        };
+
+        let link_options = LinkOptions {
+            mode: matches
+                .get_one::<String>("mode")
+                .and_then(|mode| InstallMode::parse(mode))
+                .or(config.mode)
+                .unwrap_or_default(),
+            on_conflict: conflict_policy(matches),
+            dry_run,
+        };
+
+        let planned = if matches.get_flag("locked") {
+            let lock_path = matches
+                .get_one::<PathBuf>("lockfile")
+                .expect("lockfile has a default");
+            install_locked(lock_path, repo_path, &paths, &selection, link_options)?
+        } else if let Some(repo_path) = repo_path {
+            let options = CloneOptions {
+                depth: matches.get_one::<u32>("depth").copied(),
+                subdir: matches.get_one::<PathBuf>("subdir").cloned(),
+                skip_submodules: matches.get_flag("no-submodules"),
+            };
+            install_procedure_files(
+                repo_path,
+                git_ref,
+                &options,
+                &paths,
+                &selection,
+                link_options,
+                None,
+            )?
+        } else {
+            bail!("Please provide a valid path or GitHub repository");
+        };
+        if dry_run {
+            print_plan(&planned, json)?;
+        }
+    }
+
+    // Handle the 'uninstall' command
+    if let Some(matches) = matches.subcommand_matches("uninstall") {
+        let package = matches
+            .get_one::<String>("package")
+            .expect("package is required");
+        let planned =
+            uninstall_package(package, &paths, matches.get_flag("remove-clone"), dry_run)?;
+        if dry_run {
+            print_plan(&[planned], json)?;
+        }
+    }
+
+    // Handle the 'update' command
+    if let Some(matches) = matches.subcommand_matches("update") {
+        let names = matches
+            .get_many::<String>("package")
+            .map(|names| names.cloned().collect())
+            .unwrap_or_default();
+        let (planned, failed) = update_packages(
+            names,
+            matches.get_flag("all"),
+            conflict_policy(matches),
+            dry_run,
+        )?;
+        if dry_run {
+            print_plan(&planned, json)?;
+        }
+        if !failed.is_empty() {
+            bail!("Could not update {}", failed.join(", "));
+        }
+    }
+
+    // Handle the 'sync' command
+    if let Some(matches) = matches.subcommand_matches("sync") {
+        let env_path = matches
+            .get_one::<PathBuf>("file")
+            .expect("file has a default");
+        let defaults = LinkOptions {
+            mode: config.mode.unwrap_or_default(),
+            on_conflict: conflict_policy(matches),
+            dry_run,
+        };
+        let planned = sync_environment(env_path, &paths, defaults)?;
+        if dry_run {
+            print_plan(&planned, json)?;
         }
     }
 
+    // Handle the 'lock' command
+    if let Some(matches) = matches.subcommand_matches("lock") {
+        let output = matches
+            .get_one::<PathBuf>("output")
+            .expect("output has a default");
+        write_lockfile(output)?;
+    }
+
+    // Handle the 'list' command
+    if let Some(matches) = matches.subcommand_matches("list") {
+        list_packages(matches.get_flag("json"))?;
+    }
+
+    // Handle the 'versions' command
+    if matches.subcommand_matches("versions").is_some() {
+        list_igor_versions(&paths)?;
+    }
+
     Ok(())
 }
 
-// Install procedure files from Git or local path
-fn install_procedure_files(repo_path: &str) -> Result<()> {
-    // Determine the path to the .igor directory in the user's home folder
-    let repo = if is_git_url(repo_path) {
-        clone_repository_into_igor(repo_path)?
+// Flags choosing what happens to files already at a destination
+fn conflict_args() -> [Arg; 3] {
+    [
+        Arg::new("skip")
+            .long("skip")
+            .action(ArgAction::SetTrue)
+            .conflicts_with_all(["backup", "force"])
+            .help("Leave files that are in the way and do not install those"),
+        Arg::new("backup")
+            .long("backup")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("force")
+            .help("Move files that are in the way to the ipac-backups folder of their User Files folder"),
+        Arg::new("force")
+            .long("force")
+            .action(ArgAction::SetTrue)
+            .help("Delete files that are in the way"),
+    ]
+}
+
+// Conflict policy chosen by the flags from `conflict_args`
+fn conflict_policy(matches: &clap::ArgMatches) -> ConflictPolicy {
+    if matches.get_flag("skip") {
+        ConflictPolicy::Skip
+    } else if matches.get_flag("backup") {
+        ConflictPolicy::Backup
+    } else if matches.get_flag("force") {
+        ConflictPolicy::Force
     } else {
-        Path::new(repo_path).to_path_buf()
+        ConflictPolicy::Abort
+    }
+}
+
+// Flags that print the changes a command would make instead of making them
+fn dry_run_args() -> [Arg; 2] {
+    [
+        Arg::new("dry-run")
+            .long("dry-run")
+            .action(ArgAction::SetTrue)
+            .help(
+                "Print what would be cloned, linked, copied and removed without changing anything",
+            ),
+        Arg::new("json")
+            .long("json")
+            .action(ArgAction::SetTrue)
+            .requires("dry-run")
+            .help("Print the dry run as JSON"),
+    ]
+}
+
+// Whether a subcommand was given `--dry-run`, and `--json` along with it
+fn dry_run_flags(matches: &clap::ArgMatches) -> (bool, bool) {
+    let flag = |name| {
+        matches
+            .try_get_one::<bool>(name)
+            .ok()
+            .flatten()
+            .copied()
+            .unwrap_or(false)
+    };
+    let dry_run = flag("dry-run");
+    (dry_run, dry_run && flag("json"))
+}
+
+// Install procedure files from Git or local path, along with the packages they depend on
+//
+// Locked installs pass the lockfile entry instead of resolving dependencies, because
+// the lockfile already lists every dependency at its exact commit.
+fn install_procedure_files(
+    repo_path: &str,
+    git_ref: Option<GitRef>,
+    options: &CloneOptions,
+    paths: &IgorPaths,
+    selection: &VersionSelection,
+    link_options: LinkOptions,
+    locked: Option<&LockedPackage>,
+) -> Result<Vec<PlannedPackage>> {
+    let plans = plan_install(
+        repo_path,
+        git_ref,
+        options,
+        paths,
+        selection,
+        link_options,
+        locked,
+    )?;
+    apply_install(plans, link_options)
+}
+
+// Fetch a package and the packages it depends on and work out their links,
+// checking every package for conflicts before anything is linked
+fn plan_install(
+    repo_path: &str,
+    git_ref: Option<GitRef>,
+    options: &CloneOptions,
+    paths: &IgorPaths,
+    selection: &VersionSelection,
+    link_options: LinkOptions,
+    locked: Option<&LockedPackage>,
+) -> Result<Vec<PackagePlan>> {
+    let registry = Registry::load()?;
+    let mut root = fetch_package(repo_path, git_ref, None, options)?;
+    let packages = match locked {
+        None => resolve_dependencies(root, &registry)?,
+        Some(locked) => {
+            // Keep following what the package followed before it was locked
+            if let Some(git_ref) = &locked.git_ref {
+                if let GitRef::Branch(name) = git_ref {
+                    attach_branch(&root.root, name)?;
+                }
+                root.git_ref = Some(git_ref.clone());
+            }
+            root.version_req = locked
+                .version_req
+                .as_deref()
+                .map(VersionReq::parse)
+                .transpose()?;
+            vec![root]
+        }
     };
 
-    let repo_dir = repo.canonicalize()?;
+    let mut plans = packages
+        .into_iter()
+        .map(|package| plan_package(package, paths, selection, link_options.mode, &registry))
+        .collect::<Result<Vec<_>>>()?;
+
+    // Check every package before linking any of them
+    let conflicts: Vec<_> = plans
+        .iter()
+        .flat_map(|plan| {
+            plan.targets.iter().flat_map(|target| {
+                find_conflicts(&plan.package.dir, &target.recorded, &target.expected)
+                    .into_iter()
+                    .map(|link| (link, describe_conflict(link, &target.recorded)))
+            })
+        })
+        .collect();
+    let duplicates = find_duplicates(
+        plans
+            .iter()
+            .flat_map(|plan| &plan.targets)
+            .flat_map(|target| &target.expected),
+    );
+    ensure_no_conflicts(&conflicts, &duplicates, link_options.on_conflict)?;
+
+    for plan in &mut plans {
+        for target in &mut plan.targets {
+            let (operations, linked) = plan_sync(
+                &plan.package.dir,
+                &target.recorded,
+                &target.expected,
+                std::slice::from_ref(&target.igor_install.user_files),
+                link_options,
+            )?;
+            target.operations = operations;
+            target.linked = linked;
+        }
+    }
+    Ok(plans)
+}
+
+// Link planned packages and save the registry, or undo all of it
+fn apply_install(
+    plans: Vec<PackagePlan>,
+    link_options: LinkOptions,
+) -> Result<Vec<PlannedPackage>> {
+    let planned = plans.iter().map(PackagePlan::summary).collect();
+    if link_options.dry_run {
+        return Ok(planned);
+    }
 
-    // Verify folder structure
-    let user_dir = repo_dir.join("user");
-    let igor_dir = repo_dir.join("igor");
+    let mut registry = Registry::load()?;
+    let user_files: Vec<_> = plans
+        .iter()
+        .flat_map(|plan| {
+            plan.targets
+                .iter()
+                .map(|target| &target.igor_install.user_files)
+                .chain(&plan.dropped)
+        })
+        .cloned()
+        .collect();
+    let mut transaction = Transaction::begin(&user_files)?;
+    let result = link_packages(plans, link_options, &mut transaction, &mut registry);
+    transaction.finish(result)?;
+    Ok(planned)
+}
 
-    if !user_dir.exists() || !igor_dir.exists() {
-        println!("The required 'user' and 'igor' directories are missing. Please modify the repository structure.");
-        exit(1);
+// Link planned packages in order and save the registry
+fn link_packages(
+    plans: Vec<PackagePlan>,
+    options: LinkOptions,
+    transaction: &mut Transaction,
+    registry: &mut Registry,
+) -> Result<()> {
+    for plan in plans {
+        link_package(plan, options, transaction, registry)?;
     }
+    registry.save()
+}
 
-    // Get the highest Igor Pro version installed
-    let igor_version = find_highest_igor_version()?;
+// The links one package gets in one Igor Pro installation
+struct TargetPlan {
+    igor_install: IgorInstall,
+    // Links an earlier install of the package left in this installation
+    recorded: Vec<InstalledLink>,
+    expected: Vec<InstalledLink>,
+    // What `plan_sync` worked out once conflicts were checked
+    operations: Vec<Operation>,
+    linked: Vec<InstalledLink>,
+}
 
-    // Get Igor Pro paths for User Procedures and Igor Procedures
-    let user_procs = get_wave_metrics_path(&igor_version, "User Procedures")?;
-    let igor_procs = get_wave_metrics_path(&igor_version, "Igor Procedures")?;
+// A fetched package and where its files will go, worked out before anything is linked
+struct PackagePlan {
+    package: FetchedPackage,
+    // Whether the package is new or was installed before
+    action: PackageAction,
+    clone: Option<CloneChange>,
+    targets: Vec<TargetPlan>,
+    // Links to remove from Igor Pro installations the package no longer goes into,
+    // and the User Files folders of those installations
+    removals: Vec<Operation>,
+    dropped: Vec<PathBuf>,
+    // Environment file the package is installed for by `ipac sync`
+    env: Option<PathBuf>,
+}
+
+impl PackagePlan {
+    // Everything the plan changes, as printed by `--dry-run`
+    fn summary(&self) -> PlannedPackage {
+        PlannedPackage {
+            name: self.package.manifest.package.name.clone(),
+            version: self.package.manifest.package.version.clone(),
+            action: self.action,
+            clone: self.clone.clone(),
+            operations: self
+                .removals
+                .iter()
+                .chain(self.targets.iter().flat_map(|target| &target.operations))
+                .cloned()
+                .collect(),
+        }
+    }
+}
+
+// Work out the links a fetched package needs in the selected Igor Pro installations
+fn plan_package(
+    mut package: FetchedPackage,
+    paths: &IgorPaths,
+    selection: &VersionSelection,
+    mode: InstallMode,
+    registry: &Registry,
+) -> Result<PackagePlan> {
+    // Resolve what goes where
+    let mappings = package.manifest.mappings(&package.dir)?;
+
+    // Get the Igor Pro versions to install into
+    let igor_installs = package
+        .manifest
+        .supported_installs(paths.find_selected_installs(selection)?)?;
+
+    // Links left by an earlier install of the package are re-synced rather than duplicated
+    let installed = registry.get_same_source(
+        &package.manifest.package.name,
+        &package.source,
+        package.subdir.as_deref(),
+    )?;
+    let action = match installed {
+        Some(_) => PackageAction::Reinstall,
+        None => PackageAction::Install,
+    };
+    let previous = installed
+        .map(|installed| installed.links.clone())
+        .unwrap_or_default();
+
+    // A dry run reads a scratch clone but plans against the clone under ~/.igor
+    let clone = clone_change(&package);
+    let real_dir = real_clone_path(&package.dir);
+    let scratch_dir = std::mem::replace(&mut package.dir, real_dir);
+    package.root = real_clone_path(&package.root);
+
+    let mut targets = Vec::new();
+    for igor_install in igor_installs {
+        // Link or copy every mapped file or folder
+        let mut expected = plan_links(&mappings, &igor_install.user_files, mode)?;
+        for link in &mut expected {
+            if let Ok(relative) = link.source.strip_prefix(&scratch_dir) {
+                link.source = package.dir.join(relative);
+            }
+        }
+        let recorded = previous
+            .iter()
+            .filter(|link| link.destination.starts_with(&igor_install.user_files))
+            .cloned()
+            .collect();
+        targets.push(TargetPlan {
+            igor_install,
+            recorded,
+            expected,
+            operations: Vec::new(),
+            linked: Vec::new(),
+        });
+    }
+    Ok(PackagePlan {
+        package,
+        action,
+        clone,
+        targets,
+        removals: Vec::new(),
+        dropped: Vec::new(),
+        env: None,
+    })
+}
+
+// How a dry run's scratch clone differs from the clone under ~/.igor, if at all
+fn clone_change(package: &FetchedPackage) -> Option<CloneChange> {
+    let real_root = real_clone_path(&package.root);
+    if real_root == package.root {
+        return None;
+    }
+    let git_ref = package.git_ref.as_ref().map(ToString::to_string);
+    let commit = head_commit(&package.root);
+    if !real_root.exists() {
+        return Some(CloneChange::Clone {
+            url: package.source.clone(),
+            path: real_root,
+            git_ref,
+            commit,
+        });
+    }
+    let from = head_commit(&real_root);
+    (from != commit).then_some(CloneChange::Checkout {
+        path: real_root,
+        git_ref,
+        from,
+        to: commit,
+    })
+}
 
-    // Create symbolic links for user and igor procedure files
-    link_files(&user_dir, &user_procs)?;
-    link_files(&igor_dir, &igor_procs)?;
+// Link a planned package into its Igor Pro installations and record it
+fn link_package(
+    plan: PackagePlan,
+    options: LinkOptions,
+    transaction: &mut Transaction,
+    registry: &mut Registry,
+) -> Result<()> {
+    let PackagePlan {
+        package,
+        targets,
+        removals,
+        dropped,
+        env,
+        ..
+    } = plan;
+    let FetchedPackage {
+        kind,
+        source,
+        dir: repo_dir,
+        root,
+        subdir,
+        git_ref,
+        version_req,
+        manifest,
+    } = package;
 
     println!(
-        "Successfully installed procedures for Igor Pro {}",
-        igor_version
+        "Installing {} {}",
+        manifest.package.name, manifest.package.version
     );
+    if let Some(description) = &manifest.package.description {
+        println!("  {}", description);
+    }
+    if !manifest.package.authors.is_empty() {
+        println!("  by {}", manifest.package.authors.join(", "));
+    }
+
+    transaction.apply_all(&removals)?;
+    registry.forget_targets(&manifest.package.name, &dropped);
+    // A package stays managed by the environment that installed it
+    let env = env.or_else(|| {
+        registry
+            .get(&manifest.package.name)
+            .and_then(|installed| installed.env.clone())
+    });
+
+    let mut links = Vec::new();
+    for target in &targets {
+        transaction.apply_all(&target.operations)?;
+        links.extend(target.linked.iter().cloned());
+
+        println!(
+            "Successfully installed procedures for Igor Pro {}",
+            target.igor_install
+        );
+    }
+
+    // Remember what was installed so it can be listed, updated and uninstalled later
+    registry.record(InstalledPackage {
+        name: manifest.package.name.clone(),
+        version: manifest.package.version.clone(),
+        kind,
+        source,
+        commit: head_commit(&root),
+        git_ref,
+        version_req: version_req.map(|req| req.to_string()),
+        path: repo_dir,
+        mode: options.mode,
+        subdir,
+        dependencies: manifest.dependencies.keys().cloned().collect(),
+        env,
+        targets: targets
+            .iter()
+            .map(|target| InstalledTarget {
+                igor_version: target.igor_install.version.to_string(),
+                user_files: target.igor_install.user_files.clone(),
+            })
+            .collect(),
+        links,
+    });
     Ok(())
 }
 
-// Find the highest version of Igor Pro installed on the system
-fn find_highest_igor_version() -> Result<String> {
-    let igor_dir = Path::new("C:/Program Files/WaveMetrics");
-    let versions: Vec<_> = fs::read_dir(igor_dir)?
-        .filter_map(|entry| entry.ok())
-        .filter(|entry| entry.path().is_dir())
-        .filter_map(|entry| entry.file_name().into_string().ok())
+// Install missing packages, reinstall changed ones and remove ones no longer listed
+//
+// Only packages an earlier sync with the same file installed are removed; packages
+// installed by hand are left alone.
+fn sync_environment(
+    env_path: &Path,
+    paths: &IgorPaths,
+    defaults: LinkOptions,
+) -> Result<Vec<PlannedPackage>> {
+    let env = EnvFile::load(env_path)?;
+    let env_file = env_path.canonicalize()?;
+    let wanted: Vec<(String, Option<PathBuf>)> = env
+        .packages
+        .iter()
+        .map(|package| (package.registry_source(), package.subdir.clone()))
         .collect();
 
-    if versions.is_empty() {
-        println!("No Igor Pro installations found.");
-        exit(1);
+    // Dependencies of listed packages stay installed even though they are not listed
+    let registry = Registry::load()?;
+    let required = registry.with_dependencies(
+        registry
+            .packages
+            .iter()
+            .filter(|installed| {
+                wanted.iter().any(|(source, subdir)| {
+                    *source == installed.source && *subdir == installed.subdir
+                })
+            })
+            .map(|installed| installed.name.clone())
+            .collect(),
+    );
+    let mut planned = Vec::new();
+    for installed in registry.packages {
+        if !required.contains(&installed.name) && installed.env.as_ref() == Some(&env_file) {
+            message!("Removing {}: it is no longer listed", installed.name);
+            planned.push(uninstall_package(
+                &installed.name,
+                paths,
+                false,
+                defaults.dry_run,
+            )?);
+        }
     }
 
-    // Sort versions in descending order and pick the first (highest) one
-    let highest_version = versions
+    for package in &env.packages {
+        let source = package.registry_source();
+        let selection = package.selection();
+        let registry = Registry::load()?;
+        let installed = registry
+            .packages
+            .iter()
+            .find(|p| p.source == source && p.subdir == package.subdir);
+
+        let mut stale = None;
+        match installed {
+            None => {}
+            Some(installed) if env_package_changed(package, installed, paths, defaults.mode)? => {
+                message!("Reinstalling {}: its entry changed", installed.name);
+                // The reinstall re-syncs the links it keeps and removes those in Igor Pro
+                // versions the entry no longer selects, all in one transaction
+                let kept = env_user_files(package, installed, paths)?;
+                let dropped: Vec<PathBuf> = installed
+                    .user_files()
+                    .into_iter()
+                    .filter(|user_files| !kept.contains(user_files))
+                    .collect();
+                let mut removed = installed.clone();
+                removed.links.retain(|link| {
+                    !kept
+                        .iter()
+                        .any(|user_files| link.destination.starts_with(user_files))
+                });
+                stale = Some((installed.name.clone(), plan_removal(&removed), dropped));
+            }
+            Some(installed) => {
+                message!("{} is up to date", installed.name);
+                continue;
+            }
+        }
+        let link_options = LinkOptions {
+            mode: package.mode.unwrap_or(defaults.mode),
+            ..defaults
+        };
+        let mut plans = plan_install(
+            &package.install_source(),
+            package.git_ref(),
+            &package.clone_options(),
+            paths,
+            &selection,
+            link_options,
+            None,
+        )?;
+        // Packages that were installed by hand stay unmanaged
+        for plan in &mut plans {
+            if plan.action == PackageAction::Install {
+                plan.env = Some(env_file.clone());
+            }
+        }
+        if let Some((name, removals, dropped)) = stale {
+            if let Some(plan) = plans
+                .iter_mut()
+                .find(|plan| plan.package.manifest.package.name == name)
+            {
+                plan.removals = removals;
+                plan.dropped = dropped;
+            }
+        }
+        planned.extend(apply_install(plans, link_options)?);
+    }
+    Ok(planned)
+}
+
+// Check whether an installed package no longer matches its environment entry
+fn env_package_changed(
+    package: &EnvPackage,
+    installed: &InstalledPackage,
+    paths: &IgorPaths,
+    default_mode: InstallMode,
+) -> Result<bool> {
+    if package.mode.unwrap_or(default_mode) != installed.mode {
+        return Ok(true);
+    }
+    if let Some(requested) = package.requested_ref_name() {
+        if installed.git_ref.as_ref().map(GitRef::name) != Some(requested.as_str()) {
+            return Ok(true);
+        }
+    }
+    let installed_req = installed
+        .version_req
+        .as_deref()
+        .and_then(|req| VersionReq::parse(req).ok());
+    if package.version_req() != installed_req {
+        return Ok(true);
+    }
+
+    let mut wanted = env_user_files(package, installed, paths)?;
+    let mut current: Vec<_> = installed
+        .targets
+        .iter()
+        .map(|target| target.user_files.clone())
+        .collect();
+    wanted.sort();
+    current.sort();
+    Ok(wanted != current)
+}
+
+// User Files folders an environment entry selects that the installed package supports
+fn env_user_files(
+    package: &EnvPackage,
+    installed: &InstalledPackage,
+    paths: &IgorPaths,
+) -> Result<Vec<PathBuf>> {
+    let manifest = Manifest::load(&installed.path)?;
+    Ok(paths
+        .find_selected_installs(&package.selection())?
         .into_iter()
-        .max()
-        .expect("Failed to determine the highest Igor Pro version");
+        .filter(|igor_install| manifest.supports(igor_install))
+        .map(|igor_install| igor_install.user_files)
+        .collect())
+}
+
+// Install packages from a lockfile at exactly the commits it records
+//
+// With `source`, only that package is installed and it must be in the lockfile.
+fn install_locked(
+    lock_path: &Path,
+    source: Option<&String>,
+    paths: &IgorPaths,
+    selection: &VersionSelection,
+    link_options: LinkOptions,
+) -> Result<Vec<PlannedPackage>> {
+    let lock = LockFile::load(lock_path)?;
+    let packages: Vec<&LockedPackage> = match source {
+        Some(source) => {
+            // The lockfile has sources as recorded: expanded URLs and canonical paths
+            let (url, _) = split_url_ref(source);
+            let url = split_url_version(url).map_or(url, |(url, _)| url);
+            let locked = lock
+                .find_source(&normalize_source(url))
+                .or_else(|| lock.find_source(url));
+            match locked {
+                Some(locked) => vec![locked],
+                None => bail!(
+                    "{} is not in {:?}; run `ipac lock` first",
+                    source,
+                    lock_path
+                ),
+            }
+        }
+        None => lock.packages.iter().collect(),
+    };
 
-    Ok(highest_version)
+    let mut planned = Vec::new();
+    for locked in packages {
+        planned.extend(install_locked_package(
+            locked,
+            paths,
+            selection,
+            link_options,
+        )?);
+    }
+    Ok(planned)
 }
 
-// Clone repository into the user's $HOME/.igor folder
-fn clone_repository_into_igor(repo: &str) -> Result<PathBuf> {
-    // Get the user's home directory and append ".igor"
-    let home_dir = home_dir().expect("Could not find the user's home directory.");
-    let igor_dir = home_dir.join(".igor");
+// Install one locked package, refusing local packages whose commit has drifted
+fn install_locked_package(
+    locked: &LockedPackage,
+    paths: &IgorPaths,
+    selection: &VersionSelection,
+    link_options: LinkOptions,
+) -> Result<Vec<PlannedPackage>> {
+    let git_ref = match (locked.kind, &locked.commit) {
+        (SourceKind::Git, Some(commit)) => Some(GitRef::Rev(commit.clone())),
+        (SourceKind::Git, None) => bail!("{} has no locked commit", locked.name),
+        (SourceKind::Path, Some(commit)) => {
+            let head = head_commit(Path::new(&locked.source));
+            if head.as_ref() != Some(commit) {
+                bail!(
+                    "{} at {} is at {} but the lockfile requires {}",
+                    locked.name,
+                    locked.source,
+                    head.as_deref().unwrap_or("no commit"),
+                    commit
+                );
+            }
+            None
+        }
+        (SourceKind::Path, None) => None,
+    };
+    let options = CloneOptions {
+        subdir: locked.subdir.clone(),
+        ..CloneOptions::default()
+    };
+    install_procedure_files(
+        &locked.source,
+        git_ref,
+        &options,
+        paths,
+        selection,
+        link_options,
+        Some(locked),
+    )
+}
 
-    // Ensure the .igor directory exists
-    if !igor_dir.exists() {
-        fs::create_dir_all(&igor_dir)?;
-        println!("Created .igor directory at: {:?}", igor_dir);
+// Write every installed package and its commit to a lockfile
+fn write_lockfile(output: &Path) -> Result<()> {
+    let registry = Registry::load()?;
+    let lock = LockFile::from_registry(&registry);
+    for locked in &lock.packages {
+        if locked.commit.is_none() {
+            println!(
+                "Warning: {} is not a git checkout; its contents cannot be locked",
+                locked.name
+            );
+        }
     }
+    lock.save(output)?;
+    println!("Locked {} package(s) in {:?}", lock.packages.len(), output);
+    Ok(())
+}
 
-    // Determine the repo directory under .igor based on repo name
-    let repo_name = repo.split('/').last().unwrap_or("repository"); // Get the last part of the repo URL
-    let repo_dir = igor_dir.join(repo_name);
+// Remove the links a package created in every Igor Pro installation
+//
+// Only links that still resolve into the package are removed, so files replaced
+// by the user or installed by another package are left alone.
+fn uninstall_package(
+    package: &str,
+    paths: &IgorPaths,
+    remove_clone: bool,
+    dry_run: bool,
+) -> Result<PlannedPackage> {
+    let mut registry = Registry::load()?;
 
-    // Clone the repository into this path
-    if !repo_dir.exists() {
-        println!("Cloning repository into {:?}", repo_dir);
-        Repository::clone(repo, &repo_dir)?;
-    } else {
-        println!("Repository already exists at {:?}", repo_dir);
+    let (name, version, package_dir, operations, sharing) = match registry.find(package).cloned() {
+        Some(installed) => {
+            let dependents = registry.dependents(&installed.name);
+            if !dependents.is_empty() {
+                message!(
+                    "Warning: {} is still required by {}",
+                    installed.name,
+                    dependents.join(", ")
+                );
+            }
+            // Remove the links and forget the package together, or not at all
+            let operations = plan_removal(&installed);
+            registry.remove(&installed.name);
+            if !dry_run {
+                let mut transaction = Transaction::begin(&installed.user_files())?;
+                let result = transaction
+                    .apply_all(&operations)
+                    .and_then(|()| registry.save());
+                transaction.finish(result)?;
+            }
+
+            // Other packages installed from subdirectories of the same clone
+            let root = installed.root();
+            let sharing: Vec<String> = registry
+                .packages
+                .iter()
+                .filter(|other| other.root() == root)
+                .map(|other| other.name.clone())
+                .collect();
+            (installed.name, installed.version, root, operations, sharing)
+        }
+        // Packages installed before the registry existed are found by scanning
+        None => {
+            let package_dir = resolve_package_dir(package)?;
+            let manifest = Manifest::load(&package_dir)?;
+            let operations = remove_scanned_links(&manifest, &package_dir, paths, dry_run)?;
+            (
+                manifest.package.name,
+                manifest.package.version,
+                package_dir,
+                operations,
+                Vec::new(),
+            )
+        }
+    };
+    if !dry_run {
+        let removed = operations
+            .iter()
+            .filter(|operation| matches!(operation, Operation::Remove { .. }))
+            .count();
+        println!("Removed {} link(s) installed by {}", removed, name);
+    }
+
+    let mut clone = None;
+    if remove_clone {
+        let igor_dir = igor_home();
+        if !package_dir.exists() {
+            message!("Clone at {:?} was already removed", package_dir);
+        } else if !sharing.is_empty() {
+            message!(
+                "Not removing {:?}: {} still installed from it",
+                package_dir,
+                sharing.join(", ")
+            );
+        } else if package_dir.starts_with(igor_dir.canonicalize().unwrap_or(igor_dir)) {
+            if dry_run {
+                clone = Some(CloneChange::Remove { path: package_dir });
+            } else {
+                println!("Removing clone at {:?}", package_dir);
+                fs::remove_dir_all(&package_dir)?;
+            }
+        } else {
+            message!(
+                "Not removing {:?}: it is not a clone under {:?}",
+                package_dir,
+                igor_home()
+            );
+        }
+    }
+    Ok(PlannedPackage {
+        name,
+        version,
+        action: PackageAction::Uninstall,
+        clone,
+        operations,
+    })
+}
+
+// Remove links into a package from every mapped destination in every installation,
+// returning the removals; a dry run only looks for them
+//
+// The removals run as one transaction, so an interrupted uninstall is undone.
+fn remove_scanned_links(
+    manifest: &Manifest,
+    package_dir: &Path,
+    paths: &IgorPaths,
+    dry_run: bool,
+) -> Result<Vec<Operation>> {
+    let mappings = manifest.mappings(package_dir)?;
+
+    let mut operations = Vec::new();
+    let mut user_files = Vec::new();
+    for igor_install in paths.find_installs()? {
+        for mapping in &mappings {
+            let dst_dir = igor_install.wave_metrics_path(&mapping.destination);
+            for path in package_links(package_dir, &dst_dir)? {
+                operations.push(Operation::Remove { path });
+            }
+        }
+        user_files.push(igor_install.user_files);
+    }
+
+    // Subfolders ipac created for the package are dropped on commit once empty
+    if !dry_run {
+        let mut transaction = Transaction::begin(&user_files)?;
+        let result = transaction.apply_all(&operations);
+        transaction.finish(result)?;
+    }
+    Ok(operations)
+}
+
+// Links in `dst_dir` that point into `package_dir`
+fn package_links(package_dir: &Path, dst_dir: &Path) -> Result<Vec<PathBuf>> {
+    if !dst_dir.is_dir() {
+        return Ok(Vec::new());
     }
 
-    Ok(repo_dir)
+    let mut links = Vec::new();
+    for entry in fs::read_dir(dst_dir)? {
+        let dst_path = entry?.path();
+        match link::link_target(&dst_path) {
+            Some(target) if target.starts_with(package_dir) => links.push(dst_path),
+            _ => {}
+        }
+    }
+    Ok(links)
 }
 
-// Helper to detect if a URL is a GitHub URL
-fn is_git_url(repo: &str) -> bool {
-    repo.starts_with("http") || repo.starts_with("git")
+// Find a package by path, or by name among the clones under ~/.igor
+fn resolve_package_dir(package: &str) -> Result<PathBuf> {
+    let path = Path::new(package);
+    let package_dir = if path.exists() {
+        path.to_path_buf()
+    } else {
+        igor_home().join(package)
+    };
+
+    if !package_dir.exists() {
+        bail!("Package {:?} was not found", package);
+    }
+    Ok(package_dir.canonicalize()?)
 }
 
-// Get WaveMetrics path based on version and folder type (User Procedures or Igor Procedures)
-fn get_wave_metrics_path(version: &str, folder_type: &str) -> Result<PathBuf> {
-    let doc_dir = dirs::document_dir().expect("Could not locate the user's Documents folder.");
-    let path = doc_dir.join(format!(
-        "WaveMetrics/Igor Pro {} User Files/{}",
-        version, folder_type
-    ));
-    Ok(path)
+// Update the named packages, or every installed package with `all`, returning what
+// was updated and the names of the packages that could not be
+fn update_packages(
+    names: Vec<String>,
+    all: bool,
+    on_conflict: ConflictPolicy,
+    dry_run: bool,
+) -> Result<(Vec<PlannedPackage>, Vec<String>)> {
+    let mut registry = Registry::load()?;
+    let names = if all {
+        registry.packages.iter().map(|p| p.name.clone()).collect()
+    } else {
+        names
+    };
+
+    let mut planned = Vec::new();
+    let mut failed = Vec::new();
+    for name in names {
+        let Some(installed) = registry.get(&name).cloned() else {
+            message!("Package {} is not installed", name);
+            failed.push(name);
+            continue;
+        };
+
+        // Keep going so one unreachable remote does not block the other packages
+        match update_package(installed, on_conflict, dry_run, &mut registry) {
+            Ok(plan) => planned.push(plan),
+            Err(err) => {
+                message!("Failed to update {}: {:#}", name, err);
+                failed.push(name);
+                // Drop anything the failed update changed in memory
+                registry = Registry::load()?;
+            }
+        }
+    }
+
+    Ok((planned, failed))
 }
 
-// Helper to link files and directories
-fn link_files(src_dir: &Path, dst_dir: &Path) -> Result<()> {
-    for entry in fs::read_dir(src_dir)? {
-        let entry = entry?;
-        let src_path = entry.path();
-        let dst_path = dst_dir.join(entry.file_name());
+// Pull new commits for a git package and bring its links in line with the package contents
+//
+// The clone is only fetched while the update is planned: the links are worked out
+// from a preview checkout of the new commit, then the clone moves in one transaction
+// with the links and the registry entry, so either all of them change or none do. A
+// dry run fetches into a scratch copy of the clone instead.
+fn update_package(
+    mut installed: InstalledPackage,
+    on_conflict: ConflictPolicy,
+    dry_run: bool,
+    registry: &mut Registry,
+) -> Result<PlannedPackage> {
+    message!("Updating {}", installed.name);
+    let real_root = installed.root();
+    let root = match installed.kind {
+        SourceKind::Git => working_clone(&real_root)?,
+        SourceKind::Path => real_root.clone(),
+    };
 
-        // Create symbolic links from the repository's user/igor folder to the corresponding Igor directory
-        if src_path.is_file() {
-            println!(
-                "Creating symbolic link from {:?} to {:?}",
-                &src_path, &dst_path
-            );
-            symlink_file(&src_path, &dst_path)?;
-        } else if src_path.is_dir() {
-            println!(
-                "Creating symbolic link from {:?} to {:?}",
-                &src_path, &dst_path
-            );
-            symlink_dir(&src_path, &dst_path)?;
+    // Work out the commit the clone moves to, if any, without moving it yet
+    let mut target = None;
+    let pinned = installed
+        .git_ref
+        .as_ref()
+        .filter(|git_ref| git_ref.is_pinned());
+    if let Some(req) = &installed.version_req {
+        let req = VersionReq::parse(req)?;
+        match fetch_matching_tag(&root, &req)? {
+            Some((tag, commit)) => {
+                message!("Moving to tag {} for {}", tag, req);
+                installed.git_ref = Some(GitRef::Tag(tag));
+                target = Some(commit);
+            }
+            None => message!("Already at the highest tag matching {}", req),
+        }
+    } else if let Some(git_ref) = pinned {
+        message!("Pinned to {}, not fetching", git_ref);
+    } else if installed.kind == SourceKind::Git {
+        match fetch_fast_forward(&root)? {
+            UpdateResult::UpToDate(commit) => {
+                message!("Already up to date at {:.8}", commit.to_string())
+            }
+            UpdateResult::FastForwarded { from, to, commits } => {
+                message!(
+                    "Updating {:.8}..{:.8} ({} new commit(s))",
+                    from.to_string(),
+                    to.to_string(),
+                    commits
+                );
+                target = Some(to);
+            }
+        }
+    }
+
+    // Plan against a checkout of the new commit while the clone stays where it is
+    let preview = target
+        .map(|commit| PreviewClone::start(&root, commit))
+        .transpose()?;
+    let preview_root = preview.as_ref().map_or(root.as_path(), PreviewClone::path);
+    let package_dir = match &installed.subdir {
+        Some(subdir) => preview_root.join(subdir),
+        None => preview_root.to_path_buf(),
+    };
+
+    let manifest = Manifest::load(&package_dir)?;
+    let mappings = manifest.mappings(&package_dir)?;
+    let mut expected = Vec::new();
+    for target in &installed.targets {
+        expected.extend(plan_links(&mappings, &target.user_files, installed.mode)?);
+    }
+    for link in &mut expected {
+        if let Ok(relative) = link.source.strip_prefix(&package_dir) {
+            link.source = installed.path.join(relative);
+        }
+    }
+    drop(preview);
+
+    // Copies edited since they were installed are conflicts too, checked before the
+    // clone moves: a hard link shares its file with the clone
+    let conflicts: Vec<_> = find_conflicts(&installed.path, &installed.links, &expected)
+        .into_iter()
+        .map(|link| (link, describe_conflict(link, &installed.links)))
+        .collect();
+    ensure_no_conflicts(&conflicts, &find_duplicates(&expected), on_conflict)?;
+
+    let user_files = installed.user_files();
+    let options = LinkOptions {
+        mode: installed.mode,
+        on_conflict,
+        dry_run,
+    };
+    let (operations, links) = plan_sync(
+        &installed.path,
+        &installed.links,
+        &expected,
+        &user_files,
+        options,
+    )?;
+    let from = head_commit(&real_root);
+    if target.is_some() && from.is_none() {
+        bail!("{:?} has no commit checked out to update from", real_root);
+    }
+    let commit = target
+        .map(|commit| commit.to_string())
+        .or_else(|| head_commit(&root));
+    let clone = target.map(|to| CloneChange::Checkout {
+        path: real_root.clone(),
+        git_ref: installed.git_ref.as_ref().map(ToString::to_string),
+        from: from.clone(),
+        to: Some(to.to_string()),
+    });
+    let planned = PlannedPackage {
+        name: installed.name.clone(),
+        version: manifest.package.version.clone(),
+        action: PackageAction::Update,
+        clone,
+        operations,
+    };
+    if dry_run {
+        return Ok(planned);
+    }
+
+    let previous = std::mem::replace(&mut installed.links, links);
+    let mode = installed.mode;
+    installed.version = manifest.package.version;
+    installed.commit = commit.clone();
+
+    let mut transaction = Transaction::begin(&user_files)?;
+    let moved = match (&from, &commit) {
+        (Some(from), Some(to)) if target.is_some() => detach_edited_hard_links(&previous, mode)
+            .and_then(|()| transaction.move_clone(&real_root, from, to)),
+        _ => Ok(()),
+    };
+    let result = moved
+        .and_then(|()| transaction.apply_all(&planned.operations))
+        .and_then(|()| {
+            registry.record(installed);
+            registry.save()
+        });
+    transaction.finish(result)?;
+    Ok(planned)
+}
+
+// Give every hard link the user edited a file of its own in the clone, so moving the
+// clone cannot write through to the edited copy
+fn detach_edited_hard_links(links: &[InstalledLink], mode: InstallMode) -> Result<()> {
+    if mode != InstallMode::Hardlink {
+        return Ok(());
+    }
+    for installed_link in links {
+        if installed_link.status() == LinkStatus::Modified {
+            link::detach_file(&installed_link.source)?;
         }
     }
     Ok(())
 }
+
+// Summary of an installed package as printed by `ipac list`
+#[derive(Serialize)]
+#[serde(rename_all = "kebab-case")]
+struct PackageSummary<'a> {
+    name: &'a str,
+    version: &'a str,
+    kind: SourceKind,
+    source: &'a str,
+    mode: InstallMode,
+    commit: Option<&'a str>,
+    git_ref: Option<String>,
+    igor_versions: Vec<&'a str>,
+    links: usize,
+    health: LinkStatus,
+}
+
+// Print every package in the registry with its source, targets and link health
+fn list_packages(json: bool) -> Result<()> {
+    let registry = Registry::load()?;
+    let summaries: Vec<_> = registry
+        .packages
+        .iter()
+        .map(|package| PackageSummary {
+            name: &package.name,
+            version: &package.version,
+            kind: package.kind,
+            source: &package.source,
+            mode: package.mode,
+            commit: package.commit.as_deref(),
+            git_ref: package.git_ref.as_ref().map(GitRef::to_string),
+            igor_versions: package
+                .targets
+                .iter()
+                .map(|target| target.igor_version.as_str())
+                .collect(),
+            links: package.links.len(),
+            health: package.health(),
+        })
+        .collect();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&summaries)?);
+        return Ok(());
+    }
+
+    if summaries.is_empty() {
+        println!("No packages installed.");
+        return Ok(());
+    }
+
+    for summary in &summaries {
+        let commit = summary.commit.map(|commit| &commit[..8.min(commit.len())]);
+        let health = match summary.health {
+            LinkStatus::Present => "ok",
+            LinkStatus::Missing => "missing links",
+            LinkStatus::Dangling => "dangling links",
+            LinkStatus::Modified => "modified files",
+            LinkStatus::Overwritten => "overwritten links",
+        };
+        println!(
+            "{:<20} {:<10} {:<8} {:<16} Igor {:<10} {:<18} {}",
+            summary.name,
+            summary.version,
+            commit.unwrap_or("-"),
+            summary.git_ref.as_deref().unwrap_or("-"),
+            summary.igor_versions.join(", "),
+            health,
+            summary.source
+        );
+    }
+    Ok(())
+}
+
+// Print every detected Igor Pro installation and its User Files folder
+fn list_igor_versions(paths: &IgorPaths) -> Result<()> {
+    let installs = paths.find_installs()?;
+
+    if installs.is_empty() {
+        println!("No Igor Pro installations found.");
+        return Ok(());
+    }
+
+    for install in &installs {
+        println!(
+            "Igor Pro {:<12} {:<24} {:?}",
+            install.version.to_string(),
+            install.root,
+            install.user_files
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,421 @@
+use crate::git::GitRef;
+use crate::igor::{parse_version, IgorInstall};
+use crate::progress::message;
+use anyhow::{bail, Context, Result};
+use semver::VersionReq;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+// Name of the manifest file at the root of a package
+pub const MANIFEST_FILE: &str = "ipac.toml";
+
+// Folders under "Igor Pro N User Files" that a package may install into
+const IGOR_FOLDERS: [&str; 5] = [
+    "User Procedures",
+    "Igor Procedures",
+    "Igor Extensions",
+    "Igor Extensions (64-bit)",
+    "Igor Help Files",
+];
+
+// Contents of an ipac.toml package manifest
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub package: Package,
+    // Package paths mapped to folders under "Igor Pro N User Files"
+    #[serde(default)]
+    pub install: BTreeMap<String, String>,
+    // Other packages that must be installed first, keyed by package name
+    #[serde(default)]
+    pub dependencies: BTreeMap<String, Dependency>,
+}
+
+// Where to get a dependency and which ref of it to use
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Dependency {
+    pub git: Option<String>,
+    // Local folder, relative to the package that declares the dependency
+    pub path: Option<PathBuf>,
+    pub branch: Option<String>,
+    pub tag: Option<String>,
+    pub rev: Option<String>,
+    // Semver requirement matched against the repository's tags, e.g. "^1.2"
+    pub version: Option<String>,
+    // Folder of the repository or local path that holds the package
+    pub subdir: Option<PathBuf>,
+}
+
+impl Dependency {
+    // Check that the dependency names exactly one source and at most one ref
+    fn validate(&self) -> Result<()> {
+        match (&self.git, &self.path) {
+            (Some(_), Some(_)) => bail!("Give either `git` or `path`, not both"),
+            (None, None) => bail!("Give a `git` URL or a local `path`"),
+            _ => {}
+        }
+        let refs = [&self.branch, &self.tag, &self.rev, &self.version]
+            .iter()
+            .filter(|git_ref| git_ref.is_some())
+            .count();
+        if refs > 1 {
+            bail!("Give at most one of `branch`, `tag`, `rev` and `version`");
+        }
+        if refs > 0 && self.path.is_some() {
+            bail!("`branch`, `tag`, `rev` and `version` only apply to git dependencies");
+        }
+        if let Some(version) = &self.version {
+            VersionReq::parse(version)
+                .with_context(|| format!("Invalid version requirement {:?}", version))?;
+        }
+        Ok(())
+    }
+
+    // The source to install from, resolving local paths against the declaring package
+    pub fn source(&self, package_dir: &Path) -> String {
+        match (&self.git, &self.path) {
+            (Some(git), _) => git.clone(),
+            (None, Some(path)) => package_dir.join(path).to_string_lossy().into_owned(),
+            (None, None) => unreachable!("validated on load"),
+        }
+    }
+
+    // The requested ref, if any
+    pub fn git_ref(&self) -> Option<GitRef> {
+        if let Some(branch) = &self.branch {
+            Some(GitRef::Branch(branch.clone()))
+        } else if let Some(tag) = &self.tag {
+            Some(GitRef::Tag(tag.clone()))
+        } else {
+            self.rev.clone().map(GitRef::Rev)
+        }
+    }
+
+    // The semver requirement, if any; checked by `validate`
+    pub fn version_req(&self) -> Option<VersionReq> {
+        VersionReq::parse(self.version.as_deref()?).ok()
+    }
+}
+
+// The [package] table of a manifest
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Package {
+    pub name: String,
+    pub version: String,
+    #[serde(default)]
+    pub authors: Vec<String>,
+    pub description: Option<String>,
+    // Oldest Igor Pro version the package supports, e.g. "8" or "8.04"
+    pub igor_version: Option<String>,
+}
+
+// A resolved source inside the package and where it is installed
+#[derive(Debug, Clone)]
+pub struct Mapping {
+    pub source: PathBuf,
+    // Destination relative to the "Igor Pro N User Files" folder
+    pub destination: PathBuf,
+}
+
+impl Manifest {
+    // Read ipac.toml from `package_dir`, falling back to the user/igor convention
+    pub fn load(package_dir: &Path) -> Result<Manifest> {
+        let path = package_dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Manifest::conventional(package_dir));
+        }
+
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        let manifest: Manifest =
+            toml::from_str(&contents).with_context(|| format!("Failed to parse {:?}", path))?;
+        manifest
+            .validate()
+            .with_context(|| format!("Invalid manifest {:?}", path))?;
+        Ok(manifest)
+    }
+
+    // Manifest for a package without ipac.toml, named after its folder
+    fn conventional(package_dir: &Path) -> Manifest {
+        let name = package_dir
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "package".to_string());
+
+        Manifest {
+            package: Package {
+                name,
+                version: "0.0.0".to_string(),
+                authors: Vec::new(),
+                description: None,
+                igor_version: None,
+            },
+            install: BTreeMap::new(),
+            dependencies: BTreeMap::new(),
+        }
+    }
+
+    // Check the fields that serde cannot check on its own
+    fn validate(&self) -> Result<()> {
+        let name = &self.package.name;
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        {
+            bail!(
+                "Package name {:?} may only contain letters, digits, '-', '_' and '.'",
+                name
+            );
+        }
+        if self.package.version.trim().is_empty() {
+            bail!("Package version must not be empty");
+        }
+        if let Some(igor_version) = &self.package.igor_version {
+            if parse_version(igor_version).is_none() {
+                bail!(
+                    "Invalid igor-version {:?}, expected e.g. \"8\" or \"8.04\"",
+                    igor_version
+                );
+            }
+        }
+
+        for (name, dependency) in &self.dependencies {
+            if name == &self.package.name {
+                bail!("Package {} cannot depend on itself", name);
+            }
+            dependency
+                .validate()
+                .with_context(|| format!("Invalid dependency {}", name))?;
+        }
+
+        for (source, destination) in &self.install {
+            if !is_relative_inside(Path::new(source)) {
+                bail!(
+                    "Install source {:?} must be a relative path inside the package",
+                    source
+                );
+            }
+            let destination = Path::new(destination);
+            let folder = destination.components().next();
+            let known = IGOR_FOLDERS
+                .iter()
+                .any(|known| folder == Some(Component::Normal(known.as_ref())));
+            if !known || !is_relative_inside(destination) {
+                bail!(
+                    "Install destination {:?} must be inside one of {:?}",
+                    destination,
+                    IGOR_FOLDERS
+                );
+            }
+        }
+        Ok(())
+    }
+
+    // Resolve the install mappings against the package directory
+    //
+    // Without an [install] table the package must follow the convention of a
+    // `user` folder for User Procedures and an `igor` folder for Igor Procedures.
+    pub fn mappings(&self, package_dir: &Path) -> Result<Vec<Mapping>> {
+        if self.install.is_empty() {
+            let user_dir = package_dir.join("user");
+            let igor_dir = package_dir.join("igor");
+            if !user_dir.exists() || !igor_dir.exists() {
+                bail!(
+                    "The required 'user' and 'igor' directories are missing. Please modify the \
+                     repository structure or add an [install] table to {}.",
+                    MANIFEST_FILE
+                );
+            }
+            return Ok(vec![
+                Mapping {
+                    source: user_dir,
+                    destination: PathBuf::from("User Procedures"),
+                },
+                Mapping {
+                    source: igor_dir,
+                    destination: PathBuf::from("Igor Procedures"),
+                },
+            ]);
+        }
+
+        let mut mappings = Vec::new();
+        for (source, destination) in &self.install {
+            let source_path = package_dir.join(source);
+            if !source_path.exists() {
+                bail!("Install source {:?} does not exist in the package", source);
+            }
+            mappings.push(Mapping {
+                source: source_path,
+                destination: PathBuf::from(destination),
+            });
+        }
+        Ok(mappings)
+    }
+
+    // Check an installation against the package's minimum Igor Pro version
+    pub fn supports(&self, install: &IgorInstall) -> bool {
+        match self.package.igor_version.as_deref().and_then(parse_version) {
+            Some(minimum) => (install.version.major, install.version.minor) >= minimum,
+            None => true,
+        }
+    }
+
+    // Drop installations older than the package's minimum Igor Pro version
+    pub fn supported_installs(&self, installs: Vec<IgorInstall>) -> Result<Vec<IgorInstall>> {
+        let (supported, unsupported): (Vec<_>, Vec<_>) = installs
+            .into_iter()
+            .partition(|install| self.supports(install));
+
+        for install in &unsupported {
+            message!(
+                "Skipping Igor Pro {}: {} requires Igor Pro {} or newer",
+                install,
+                self.package.name,
+                self.package.igor_version.as_deref().unwrap_or_default()
+            );
+        }
+        if supported.is_empty() {
+            bail!(
+                "No selected Igor Pro installation is supported by {}",
+                self.package.name
+            );
+        }
+        Ok(supported)
+    }
+}
+
+// Check that a manifest path stays inside its root
+pub fn is_relative_inside(path: &Path) -> bool {
+    path.components().next().is_some()
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Parse and validate a manifest, returning the error message if it is invalid
+    fn check(contents: &str) -> Result<Manifest, String> {
+        let manifest: Manifest = toml::from_str(contents).map_err(|err| err.to_string())?;
+        manifest
+            .validate()
+            .map_err(|err| format!("{:#}", err))
+            .map(|()| manifest)
+    }
+
+    fn with_package(rest: &str) -> String {
+        format!("[package]\nname = \"pkg\"\nversion = \"1.0.0\"\n{}", rest)
+    }
+
+    #[test]
+    fn accepts_a_valid_manifest() {
+        let manifest = check(&with_package(
+            "igor-version = \"8.04\"\n\
+             [install]\n\
+             \"procedures\" = \"User Procedures/pkg\"\n\
+             \"xop/pkg.xop\" = \"Igor Extensions (64-bit)\"\n\
+             [dependencies]\n\
+             utils = { git = \"https://example.com/utils.git\", version = \"^1.2\" }\n\
+             local = { path = \"../local\" }\n",
+        ))
+        .unwrap();
+        assert_eq!(manifest.install.len(), 2);
+        assert_eq!(manifest.dependencies.len(), 2);
+    }
+
+    #[test]
+    fn checks_the_package_name() {
+        for name in ["my-pkg", "my_pkg", "pkg.v2", "Pkg2"] {
+            let manifest = format!("[package]\nname = \"{}\"\nversion = \"1\"\n", name);
+            assert!(check(&manifest).is_ok(), "{}", name);
+        }
+        for name in ["", "my pkg", "../pkg", "pkg/sub", "pkg:1"] {
+            let manifest = format!("[package]\nname = \"{}\"\nversion = \"1\"\n", name);
+            assert!(check(&manifest).is_err(), "{:?}", name);
+        }
+        assert!(check("[package]\nname = \"pkg\"\nversion = \" \"\n").is_err());
+    }
+
+    #[test]
+    fn checks_the_igor_version() {
+        for version in ["8", "8.04", "10.0"] {
+            assert!(check(&with_package(&format!("igor-version = \"{}\"\n", version))).is_ok());
+        }
+        for version in ["", "eight", "8.x"] {
+            let err = check(&with_package(&format!("igor-version = \"{}\"\n", version)));
+            assert!(err.unwrap_err().contains("igor-version"), "{:?}", version);
+        }
+    }
+
+    #[test]
+    fn install_sources_stay_inside_the_package() {
+        for source in ["../outside", "/etc/passwd", "procs/../../outside", ""] {
+            let err = check(&with_package(&format!(
+                "[install]\n\"{}\" = \"User Procedures\"\n",
+                source
+            )));
+            assert!(err.unwrap_err().contains("Install source"), "{:?}", source);
+        }
+    }
+
+    #[test]
+    fn install_destinations_stay_inside_igor_folders() {
+        for destination in ["User Procedures", "Igor Help Files/pkg"] {
+            let manifest = with_package(&format!("[install]\n\"a\" = \"{}\"\n", destination));
+            assert!(check(&manifest).is_ok(), "{:?}", destination);
+        }
+        for destination in [
+            "Documents",
+            "./Igor Procedures",
+            "User Procedures/../..",
+            "/User Procedures",
+            "",
+            "user procedures",
+        ] {
+            let err = check(&with_package(&format!(
+                "[install]\n\"a\" = \"{}\"\n",
+                destination
+            )));
+            assert!(
+                err.unwrap_err().contains("Install destination"),
+                "{:?}",
+                destination
+            );
+        }
+    }
+
+    #[test]
+    fn dependencies_name_one_source_and_one_ref() {
+        let invalid = [
+            "{ git = \"https://example.com/a.git\", path = \"../a\" }",
+            "{ branch = \"main\" }",
+            "{ git = \"https://example.com/a.git\", branch = \"main\", tag = \"v1\" }",
+            "{ git = \"https://example.com/a.git\", rev = \"abc\", version = \"^1\" }",
+            "{ path = \"../a\", tag = \"v1\" }",
+            "{ git = \"https://example.com/a.git\", version = \"not a version\" }",
+        ];
+        for dependency in invalid {
+            let manifest = with_package(&format!("[dependencies]\na = {}\n", dependency));
+            assert!(check(&manifest).is_err(), "{}", dependency);
+        }
+        let itself = with_package("[dependencies]\npkg = { path = \"../pkg\" }\n");
+        assert!(check(&itself).unwrap_err().contains("depend on itself"));
+    }
+
+    #[test]
+    fn relative_paths_must_stay_inside() {
+        assert!(is_relative_inside(Path::new("a/b")));
+        assert!(is_relative_inside(Path::new("./a")));
+        assert!(!is_relative_inside(Path::new("")));
+        assert!(!is_relative_inside(Path::new("../a")));
+        assert!(!is_relative_inside(Path::new("a/../../b")));
+        assert!(!is_relative_inside(Path::new("/a")));
+    }
+}
@@ -0,0 +1,395 @@
+use crate::link::{self, ConflictPolicy, InstallMode, LinkOptions};
+use crate::manifest::Mapping;
+use crate::registry::{InstalledLink, InstalledPackage, LinkStatus};
+use crate::transaction::Operation;
+use anyhow::{bail, Result};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Work out how to remove the links recorded for a package that still point at their
+// recorded source
+//
+// Copies are only deleted while they match the hash recorded when they were written.
+pub fn plan_removal(installed: &InstalledPackage) -> Vec<Operation> {
+    let mut operations = Vec::new();
+    for link in &installed.links {
+        if link.is_owned(&installed.path) {
+            operations.push(Operation::Remove {
+                path: link.destination.clone(),
+            });
+            continue;
+        }
+        match link.status() {
+            LinkStatus::Missing => {}
+            LinkStatus::Modified => operations.push(Operation::Leave {
+                path: link.destination.clone(),
+                reason: format!("it was modified after {} was installed", installed.name),
+            }),
+            _ => operations.push(Operation::Leave {
+                path: link.destination.clone(),
+                reason: format!("it no longer belongs to {}", installed.name),
+            }),
+        }
+    }
+    operations
+}
+
+// Work out how to remove recorded links that are no longer wanted and create the
+// ones that are missing
+//
+// Destinations that already hold what the package would put there are kept as they
+// are. Anything else ipac did not put there, or a copy edited since, is handled by
+// `on_conflict`; callers check for clashes with `find_conflicts` first. Returns the
+// operations to run and the links in place afterwards, leaving out skipped ones.
+// Backups go under the `user_files` folder holding the destination.
+pub fn plan_sync(
+    package_dir: &Path,
+    recorded: &[InstalledLink],
+    expected: &[InstalledLink],
+    user_files: &[PathBuf],
+    options: LinkOptions,
+) -> Result<(Vec<Operation>, Vec<InstalledLink>)> {
+    let mut operations = Vec::new();
+    for link in recorded {
+        if expected
+            .iter()
+            .any(|wanted| wanted.destination == link.destination)
+        {
+            continue;
+        }
+        if link.is_owned(package_dir) {
+            operations.push(Operation::Remove {
+                path: link.destination.clone(),
+            });
+        } else if link.status() == LinkStatus::Modified {
+            operations.push(Operation::Leave {
+                path: link.destination.clone(),
+                reason: "it was modified locally".to_string(),
+            });
+        }
+    }
+
+    let mut linked = Vec::new();
+    for link in expected {
+        match planned_status(link, recorded, package_dir) {
+            LinkStatus::Present => {
+                if !recorded.contains(link) {
+                    operations.push(Operation::Leave {
+                        path: link.destination.clone(),
+                        reason: "it already matches the package".to_string(),
+                    });
+                }
+                linked.push(link.clone());
+                continue;
+            }
+            LinkStatus::Missing => {}
+            _ if is_replaceable(link, recorded, package_dir) => {
+                operations.push(Operation::Remove {
+                    path: link.destination.clone(),
+                });
+            }
+            _ => match options.on_conflict {
+                ConflictPolicy::Abort => bail!(
+                    "{:?} is in the way: {}",
+                    &link.destination,
+                    describe_conflict(link, recorded)
+                ),
+                ConflictPolicy::Skip => {
+                    operations.push(Operation::Leave {
+                        path: link.destination.clone(),
+                        reason: describe_conflict(link, recorded).to_string(),
+                    });
+                    continue;
+                }
+                ConflictPolicy::Backup => operations.push(Operation::Backup {
+                    path: link.destination.clone(),
+                    backup: link::backup_path(&link.destination, user_files),
+                }),
+                ConflictPolicy::Force => operations.push(Operation::Remove {
+                    path: link.destination.clone(),
+                }),
+            },
+        }
+        operations.push(Operation::Link {
+            source: link.source.clone(),
+            destination: link.destination.clone(),
+            mode: options.mode,
+        });
+        linked.push(link.clone());
+    }
+    Ok((operations, linked))
+}
+
+// Whether what is at a link's destination is ipac's own: a stale link into the
+// package, a copy unchanged since it was written, or a folder holding only those
+fn is_replaceable(link: &InstalledLink, recorded: &[InstalledLink], package_dir: &Path) -> bool {
+    match recorded
+        .iter()
+        .find(|previous| previous.destination == link.destination)
+    {
+        Some(previous) => previous.is_owned(package_dir),
+        None => {
+            (link.hash.is_none() && link.is_owned(package_dir))
+                || (recorded
+                    .iter()
+                    .any(|previous| previous.destination.starts_with(&link.destination))
+                    && holds_only_owned(&link.destination, recorded, package_dir))
+        }
+    }
+}
+
+// Whether `dir` is a real folder holding nothing but links and copies ipac recorded,
+// such as the folder copies were made in before switching to a folder link
+fn holds_only_owned(dir: &Path, recorded: &[InstalledLink], package_dir: &Path) -> bool {
+    let is_folder = fs::symlink_metadata(dir).is_ok_and(|meta| meta.is_dir());
+    let Ok(entries) = fs::read_dir(dir) else {
+        return false;
+    };
+    is_folder
+        && entries.flatten().all(|entry| {
+            let path = entry.path();
+            recorded
+                .iter()
+                .any(|previous| previous.destination == path && previous.is_owned(package_dir))
+                || holds_only_owned(&path, recorded, package_dir)
+        })
+}
+
+// State of a link's destination once the recorded links above it are removed, as when
+// a folder link is replaced by copies of the files in it
+fn planned_status(
+    link: &InstalledLink,
+    recorded: &[InstalledLink],
+    package_dir: &Path,
+) -> LinkStatus {
+    let under_removed = recorded.iter().any(|previous| {
+        link.destination != previous.destination
+            && link.destination.starts_with(&previous.destination)
+            && previous.is_owned(package_dir)
+    });
+    if under_removed {
+        LinkStatus::Missing
+    } else {
+        link.status()
+    }
+}
+
+// Expected links whose destination holds something ipac may not replace on its own
+pub fn find_conflicts<'a>(
+    package_dir: &Path,
+    recorded: &[InstalledLink],
+    expected: &'a [InstalledLink],
+) -> Vec<&'a InstalledLink> {
+    expected
+        .iter()
+        .filter(|link| {
+            !matches!(
+                planned_status(link, recorded, package_dir),
+                LinkStatus::Present | LinkStatus::Missing
+            )
+        })
+        .filter(|link| !is_replaceable(link, recorded, package_dir))
+        .collect()
+}
+
+// Why a destination is in the way, for messages
+pub fn describe_conflict(link: &InstalledLink, recorded: &[InstalledLink]) -> &'static str {
+    let modified = recorded.iter().any(|previous| {
+        previous.destination == link.destination && previous.status() == LinkStatus::Modified
+    });
+    if modified {
+        "it was modified locally"
+    } else {
+        "something else already exists there"
+    }
+}
+
+// Planned links that land on or around the destination of an earlier one, with why
+//
+// Two files of one package, or of a package and its dependency, cannot share a
+// destination whatever the conflict policy.
+pub fn find_duplicates<'a>(
+    expected: impl IntoIterator<Item = &'a InstalledLink>,
+) -> Vec<(&'a InstalledLink, String)> {
+    let mut seen: Vec<&InstalledLink> = Vec::new();
+    let mut duplicates = Vec::new();
+    for link in expected {
+        let earlier = seen.iter().find(|earlier| {
+            link.destination.starts_with(&earlier.destination)
+                || earlier.destination.starts_with(&link.destination)
+        });
+        match earlier {
+            Some(earlier) if earlier.destination == link.destination => {
+                duplicates.push((link, format!("{:?} is installed there too", earlier.source)))
+            }
+            Some(earlier) => duplicates.push((
+                link,
+                format!("it overlaps where {:?} is installed", earlier.source),
+            )),
+            None => seen.push(link),
+        }
+    }
+    duplicates
+}
+
+// Refuse to start when files are in the way and no conflict policy was chosen, or
+// when planned links share a destination, listing every clash at once
+pub fn ensure_no_conflicts(
+    conflicts: &[(&InstalledLink, &'static str)],
+    duplicates: &[(&InstalledLink, String)],
+    on_conflict: ConflictPolicy,
+) -> Result<()> {
+    let conflicts = match on_conflict {
+        ConflictPolicy::Abort => conflicts,
+        _ => &[],
+    };
+    if conflicts.is_empty() && duplicates.is_empty() {
+        return Ok(());
+    }
+    let mut listing: Vec<String> = conflicts
+        .iter()
+        .map(|(link, reason)| format!("  {:?}: {}", &link.destination, reason))
+        .collect();
+    listing.extend(
+        duplicates
+            .iter()
+            .map(|(link, reason)| format!("  {:?}: {}", &link.destination, reason)),
+    );
+    let mut message = format!(
+        "{} destination(s) are in the way, nothing was changed:\n{}",
+        listing.len(),
+        listing.join("\n")
+    );
+    if !conflicts.is_empty() {
+        message.push_str(
+            "\nPass --skip to leave them, --backup to move them aside or --force to replace them",
+        );
+    }
+    if !duplicates.is_empty() {
+        message.push_str(
+            "\nTwo package files cannot be installed to the same place; change the [install] \
+             table of one of the packages",
+        );
+    }
+    bail!(message)
+}
+
+// Expand mappings into one link per file or folder in each mapped source folder
+//
+// Copies and hard links are made file by file, so folders are expanded recursively
+// and every file is hashed.
+pub fn plan_links(
+    mappings: &[Mapping],
+    user_files: &Path,
+    mode: InstallMode,
+) -> Result<Vec<InstalledLink>> {
+    let mut links = Vec::new();
+    for mapping in mappings {
+        let dst_dir = user_files.join(&mapping.destination);
+        if mapping.source.is_dir() {
+            for entry in fs::read_dir(&mapping.source)? {
+                let entry = entry?;
+                plan_entry(
+                    &entry.path(),
+                    &dst_dir.join(entry.file_name()),
+                    mode,
+                    &mut links,
+                )?;
+            }
+        } else {
+            let destination = dst_dir.join(file_name(&mapping.source));
+            plan_entry(&mapping.source, &destination, mode, &mut links)?;
+        }
+    }
+    links.sort_by(|a, b| a.destination.cmp(&b.destination));
+    Ok(links)
+}
+
+// Plan the link for one file or folder of a package
+fn plan_entry(
+    source: &Path,
+    destination: &Path,
+    mode: InstallMode,
+    links: &mut Vec<InstalledLink>,
+) -> Result<()> {
+    if mode != InstallMode::Symlink && source.is_dir() {
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            plan_entry(
+                &entry.path(),
+                &destination.join(entry.file_name()),
+                mode,
+                links,
+            )?;
+        }
+        return Ok(());
+    }
+
+    let hash = match mode {
+        InstallMode::Symlink => None,
+        InstallMode::Copy | InstallMode::Hardlink => Some(link::file_hash(source)?),
+    };
+    links.push(InstalledLink {
+        source: source.to_path_buf(),
+        destination: destination.to_path_buf(),
+        hash,
+    });
+    Ok(())
+}
+
+// Final component of a path, used as the link name in the destination folder
+fn file_name(path: &Path) -> &std::ffi::OsStr {
+    path.file_name().unwrap_or(path.as_os_str())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn link(source: &str, destination: &str) -> InstalledLink {
+        InstalledLink {
+            source: PathBuf::from(source),
+            destination: PathBuf::from(destination),
+            hash: None,
+        }
+    }
+
+    #[test]
+    fn finds_links_sharing_a_destination() {
+        let expected = [
+            link("/pkg/a/x.ipf", "/uf/User Procedures/x.ipf"),
+            link("/pkg/a/y.ipf", "/uf/User Procedures/y.ipf"),
+            link("/pkg/b/x.ipf", "/uf/User Procedures/x.ipf"),
+        ];
+        let duplicates = find_duplicates(&expected);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].0.source, PathBuf::from("/pkg/b/x.ipf"));
+    }
+
+    #[test]
+    fn finds_links_inside_a_linked_folder() {
+        let expected = [
+            link("/utils/user/sub", "/uf/User Procedures/sub"),
+            link("/pkg/user/sub/x.ipf", "/uf/User Procedures/sub/x.ipf"),
+            link("/pkg/user/subway.ipf", "/uf/User Procedures/subway.ipf"),
+        ];
+        let duplicates = find_duplicates(&expected);
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates[0].0.source, PathBuf::from("/pkg/user/sub/x.ipf"));
+    }
+
+    #[test]
+    fn duplicates_are_refused_under_every_policy() {
+        let expected = [
+            link("/pkg/a/x.ipf", "/uf/x.ipf"),
+            link("/pkg/b/x.ipf", "/uf/x.ipf"),
+        ];
+        let duplicates = find_duplicates(&expected);
+        assert!(ensure_no_conflicts(&[], &duplicates, ConflictPolicy::Force).is_err());
+        assert!(ensure_no_conflicts(&[], &[], ConflictPolicy::Abort).is_ok());
+
+        let conflicts = [(&expected[0], "something else already exists there")];
+        assert!(ensure_no_conflicts(&conflicts, &[], ConflictPolicy::Abort).is_err());
+        assert!(ensure_no_conflicts(&conflicts, &[], ConflictPolicy::Skip).is_ok());
+    }
+}
@@ -0,0 +1,132 @@
+use indicatif::{HumanBytes, ProgressBar, ProgressStyle};
+use signal_hook::consts::SIGINT;
+use signal_hook::low_level;
+use std::fmt;
+use std::io::{stderr, IsTerminal};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::OnceLock;
+
+// Set by `--quiet` to hide progress bars
+static QUIET: AtomicBool = AtomicBool::new(false);
+
+// Hide progress bars for the rest of the run
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+// Set by `--dry-run --json` so stdout carries nothing but the plan
+static MESSAGES_TO_STDERR: AtomicBool = AtomicBool::new(false);
+
+// Send progress messages to stderr for the rest of the run
+pub fn set_messages_to_stderr(to_stderr: bool) {
+    MESSAGES_TO_STDERR.store(to_stderr, Ordering::Relaxed);
+}
+
+// Print a progress message: to stdout, or to stderr while stdout carries JSON
+pub fn print_message(text: fmt::Arguments<'_>) {
+    if MESSAGES_TO_STDERR.load(Ordering::Relaxed) {
+        eprintln!("{}", text);
+    } else {
+        println!("{}", text);
+    }
+}
+
+// `println!` for progress messages; see `print_message`
+macro_rules! message {
+    ($($arg:tt)*) => {
+        $crate::progress::print_message(format_args!($($arg)*))
+    };
+}
+pub(crate) use message;
+
+// Number of clones and fetches running, and whether Ctrl-C was pressed during them
+static TRANSFERS: AtomicUsize = AtomicUsize::new(0);
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Handle Ctrl-C for the rest of the process, once
+//
+// Outside a transfer Ctrl-C stops the process as usual. During one the first Ctrl-C
+// only sets `INTERRUPTED`, and a second one exits immediately.
+fn handle_interrupts() {
+    static HANDLER: OnceLock<()> = OnceLock::new();
+    HANDLER.get_or_init(|| {
+        // Only atomics and async-signal-safe calls happen in the handler
+        let _ = unsafe {
+            low_level::register(SIGINT, || {
+                if TRANSFERS.load(Ordering::SeqCst) == 0 {
+                    let _ = low_level::emulate_default_handler(SIGINT);
+                } else if INTERRUPTED.swap(true, Ordering::SeqCst) {
+                    low_level::exit(130);
+                }
+            })
+        };
+    });
+}
+
+// Progress of a clone or fetch, and whether the user pressed Ctrl-C during it
+//
+// While a transfer is running the first Ctrl-C asks git2 to stop at its next
+// callback so the caller can clean up; a second one exits immediately.
+pub struct Transfer {
+    bar: ProgressBar,
+}
+
+impl Transfer {
+    // Start reporting a transfer, showing a bar only on an interactive terminal
+    pub fn start() -> Transfer {
+        let bar = if QUIET.load(Ordering::Relaxed) || !stderr().is_terminal() {
+            ProgressBar::hidden()
+        } else {
+            ProgressBar::new(0)
+        };
+        bar.set_style(
+            ProgressStyle::with_template("{prefix:>10} [{bar:30}] {pos}/{len} {msg}")
+                .expect("valid progress template")
+                .progress_chars("=> "),
+        );
+
+        handle_interrupts();
+        // A transfer started inside another one keeps the outer one's Ctrl-C
+        if TRANSFERS.fetch_add(1, Ordering::SeqCst) == 0 {
+            INTERRUPTED.store(false, Ordering::SeqCst);
+        }
+        Transfer { bar }
+    }
+
+    // Report received objects and bytes; returns false to make git2 stop
+    pub fn objects(&self, stats: &git2::Progress<'_>) -> bool {
+        if stats.received_objects() < stats.total_objects() {
+            self.bar.set_length(stats.total_objects() as u64);
+            self.bar.set_position(stats.received_objects() as u64);
+            self.bar.set_prefix("Receiving");
+            self.bar
+                .set_message(HumanBytes(stats.received_bytes() as u64).to_string());
+        } else if stats.total_deltas() > 0 {
+            self.bar.set_length(stats.total_deltas() as u64);
+            self.bar.set_position(stats.indexed_deltas() as u64);
+            self.bar.set_prefix("Resolving");
+            self.bar.set_message("deltas");
+        }
+        !self.interrupted()
+    }
+
+    // Report files written while checking out
+    pub fn checkout(&self, current: usize, total: usize) {
+        self.bar.set_length(total as u64);
+        self.bar.set_position(current as u64);
+        self.bar.set_prefix("Checkout");
+        self.bar.set_message("files");
+    }
+
+    // Whether Ctrl-C was pressed since the transfer started
+    pub fn interrupted(&self) -> bool {
+        INTERRUPTED.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for Transfer {
+    fn drop(&mut self) {
+        self.bar.finish_and_clear();
+        TRANSFERS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
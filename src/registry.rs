@@ -0,0 +1,435 @@
+use crate::config::igor_home;
+use crate::git::GitRef;
+use crate::link::{self, InstallMode};
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+// Name of the installed-package database under ~/.igor
+pub const REGISTRY_FILE: &str = "installed.toml";
+
+// Every package ipac has installed, persisted in ~/.igor/installed.toml
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Registry {
+    #[serde(default, rename = "package")]
+    pub packages: Vec<InstalledPackage>,
+}
+
+// Where a package was installed from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum SourceKind {
+    Git,
+    Path,
+}
+
+// A package as recorded at install time
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct InstalledPackage {
+    pub name: String,
+    pub version: String,
+    pub kind: SourceKind,
+    // Git URL or local path the package was installed from
+    pub source: String,
+    // Package folder the links point into
+    pub path: PathBuf,
+    // Whether files were linked, copied or hard linked
+    #[serde(default)]
+    pub mode: InstallMode,
+    // Where `path` lies inside the clone or local folder, when not at its root
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdir: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    // Branch, tag or commit the clone was installed from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub git_ref: Option<GitRef>,
+    // Semver requirement `ipac update` moves the tag along, e.g. "^1.2"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version_req: Option<String>,
+    // Names of the packages this one was installed with as dependencies
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dependencies: Vec<String>,
+    // Environment file `ipac sync` installed the package for, so sync only removes
+    // packages it installed itself
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env: Option<PathBuf>,
+    #[serde(default)]
+    pub targets: Vec<InstalledTarget>,
+    #[serde(default)]
+    pub links: Vec<InstalledLink>,
+}
+
+// An Igor Pro installation a package was installed into
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct InstalledTarget {
+    pub igor_version: String,
+    pub user_files: PathBuf,
+}
+
+// A link ipac created, from `destination` in Igor to `source` in the package
+//
+// Copies and hard links record the hash of what was written, so local edits are
+// noticed before the file is replaced or deleted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct InstalledLink {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+}
+
+// State of a recorded link on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LinkStatus {
+    // The link exists and points at its recorded source
+    Present,
+    // Nothing exists at the destination any more
+    Missing,
+    // The link exists but its source was deleted
+    Dangling,
+    // A copied file was edited after ipac wrote it
+    Modified,
+    // Something else now lives at the destination
+    Overwritten,
+}
+
+impl InstalledLink {
+    // Inspect the destination to see whether the link is still intact
+    pub fn status(&self) -> LinkStatus {
+        let Ok(meta) = fs::symlink_metadata(&self.destination) else {
+            return LinkStatus::Missing;
+        };
+        if let Some(hash) = &self.hash {
+            return if meta.file_type().is_symlink() || !meta.is_file() {
+                LinkStatus::Overwritten
+            } else if link::file_hash(&self.destination).ok().as_ref() != Some(hash) {
+                LinkStatus::Modified
+            } else if !self.source.exists() {
+                LinkStatus::Dangling
+            } else {
+                LinkStatus::Present
+            };
+        }
+        match link::link_target(&self.destination) {
+            Some(target) if target == self.source || target == canonical(&self.source) => {
+                if target.exists() {
+                    LinkStatus::Present
+                } else {
+                    LinkStatus::Dangling
+                }
+            }
+            _ => LinkStatus::Overwritten,
+        }
+    }
+
+    // Whether the destination is still what ipac put there, so it may be replaced or removed
+    //
+    // A symlink must still point into `package_dir`; a copy must have been made from
+    // a file in `package_dir` and be unmodified.
+    pub fn is_owned(&self, package_dir: &Path) -> bool {
+        match &self.hash {
+            Some(_) => {
+                self.source.starts_with(package_dir)
+                    && matches!(self.status(), LinkStatus::Present | LinkStatus::Dangling)
+            }
+            None => link::link_target(&self.destination)
+                .is_some_and(|target| target.starts_with(package_dir)),
+        }
+    }
+}
+
+impl InstalledPackage {
+    // The clone or local folder holding the package
+    pub fn root(&self) -> PathBuf {
+        let depth = self
+            .subdir
+            .as_ref()
+            .map_or(0, |subdir| subdir.components().count());
+        self.path
+            .ancestors()
+            .nth(depth)
+            .unwrap_or(&self.path)
+            .to_path_buf()
+    }
+
+    // Source and subdirectory, for messages
+    pub fn describe_source(&self) -> String {
+        match &self.subdir {
+            Some(subdir) => format!("{} ({})", self.source, subdir.display()),
+            None => self.source.clone(),
+        }
+    }
+
+    // User Files folders the package was installed into
+    pub fn user_files(&self) -> Vec<PathBuf> {
+        self.targets
+            .iter()
+            .map(|target| target.user_files.clone())
+            .collect()
+    }
+
+    // Overall health of a package: the worst status among its links
+    pub fn health(&self) -> LinkStatus {
+        self.links
+            .iter()
+            .map(InstalledLink::status)
+            .max()
+            .unwrap_or(LinkStatus::Present)
+    }
+}
+
+impl Registry {
+    // Load the registry, returning an empty one when nothing has been installed yet
+    pub fn load() -> Result<Registry> {
+        let path = registry_path();
+        if !path.exists() {
+            return Ok(Registry::default());
+        }
+
+        let contents =
+            fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {:?}", path))
+    }
+
+    // Write the registry atomically so an interrupted run never leaves it truncated
+    pub fn save(&self) -> Result<()> {
+        let path = registry_path();
+        let contents = toml::to_string_pretty(self)?;
+        write_atomic(&path, &contents).with_context(|| format!("Failed to write {:?}", path))
+    }
+
+    // Look up a package by name
+    pub fn get(&self, name: &str) -> Option<&InstalledPackage> {
+        self.packages.iter().find(|package| package.name == name)
+    }
+
+    // Look up the package recorded under `name`, refusing when that name belongs to a
+    // package from another source
+    //
+    // Packages are keyed by name, and packages without a manifest are named after
+    // their folder, so two unrelated folders can claim the same name.
+    pub fn get_same_source(
+        &self,
+        name: &str,
+        source: &str,
+        subdir: Option<&Path>,
+    ) -> Result<Option<&InstalledPackage>> {
+        match self.get(name) {
+            Some(installed)
+                if installed.source != source || installed.subdir.as_deref() != subdir =>
+            {
+                bail!(
+                    "A package named {} is already installed from {}; uninstall it first",
+                    name,
+                    installed.describe_source()
+                )
+            }
+            installed => Ok(installed),
+        }
+    }
+
+    // Look up a package by name, or by the folder it was installed from
+    pub fn find(&self, package: &str) -> Option<&InstalledPackage> {
+        self.get(package).or_else(|| {
+            let path = Path::new(package).canonicalize().ok()?;
+            self.packages
+                .iter()
+                .find(|installed| installed.path == path)
+        })
+    }
+
+    // Forget the targets and links a package has in the given User Files folders
+    pub fn forget_targets(&mut self, name: &str, user_files: &[PathBuf]) {
+        if let Some(package) = self.packages.iter_mut().find(|p| p.name == name) {
+            let dropped = |path: &Path| user_files.iter().any(|folder| path.starts_with(folder));
+            package
+                .targets
+                .retain(|target| !dropped(&target.user_files));
+            package.links.retain(|link| !dropped(&link.destination));
+        }
+    }
+
+    // Record an install, keeping targets and links of an earlier install into other
+    // Igor Pro versions
+    pub fn record(&mut self, package: InstalledPackage) {
+        match self.packages.iter_mut().find(|p| p.name == package.name) {
+            Some(existing) => {
+                let replaced = |path: &Path| {
+                    package
+                        .targets
+                        .iter()
+                        .any(|target| path.starts_with(&target.user_files))
+                };
+                let mut targets = std::mem::take(&mut existing.targets);
+                let mut links = std::mem::take(&mut existing.links);
+                targets.retain(|target| !replaced(&target.user_files));
+                links.retain(|link| !replaced(&link.destination));
+                targets.extend(package.targets.iter().cloned());
+                links.extend(package.links.iter().cloned());
+
+                *existing = InstalledPackage {
+                    targets,
+                    links,
+                    ..package
+                };
+            }
+            None => self.packages.push(package),
+        }
+        self.packages.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    // Names of installed packages that depend on `name`
+    pub fn dependents(&self, name: &str) -> Vec<&str> {
+        self.packages
+            .iter()
+            .filter(|package| package.dependencies.iter().any(|dep| dep == name))
+            .map(|package| package.name.as_str())
+            .collect()
+    }
+
+    // Names of the given packages and everything they depend on, transitively
+    pub fn with_dependencies(&self, names: Vec<String>) -> BTreeSet<String> {
+        let mut required = BTreeSet::new();
+        let mut pending = names;
+        while let Some(name) = pending.pop() {
+            if let Some(package) = self.get(&name) {
+                if !required.contains(&name) {
+                    pending.extend(package.dependencies.iter().cloned());
+                }
+            }
+            required.insert(name);
+        }
+        required
+    }
+
+    // Forget a package, returning its record
+    pub fn remove(&mut self, name: &str) -> Option<InstalledPackage> {
+        let index = self.packages.iter().position(|p| p.name == name)?;
+        Some(self.packages.remove(index))
+    }
+}
+
+// Canonical form of a path that may no longer exist
+fn canonical(path: &Path) -> PathBuf {
+    path.canonicalize().unwrap_or_else(|_| path.to_path_buf())
+}
+
+// Location of the registry file
+pub fn registry_path() -> PathBuf {
+    igor_home().join(REGISTRY_FILE)
+}
+
+// Write `contents` to a sibling temporary file and rename it over `path`
+pub fn write_atomic(path: &Path, contents: &str) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(contents.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copies_from_another_package_are_not_owned() {
+        let dir = std::env::temp_dir().join(format!("ipac-test-owned-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("pkg")).unwrap();
+        fs::create_dir_all(dir.join("uf")).unwrap();
+        let source = dir.join("pkg").join("a.ipf");
+        let destination = dir.join("uf").join("a.ipf");
+        fs::write(&source, "a").unwrap();
+        fs::write(&destination, "a").unwrap();
+
+        let copy = InstalledLink {
+            source,
+            destination,
+            hash: Some(link::file_hash(&dir.join("uf").join("a.ipf")).unwrap()),
+        };
+        assert!(copy.is_owned(&dir.join("pkg")));
+        assert!(!copy.is_owned(&dir.join("mono").join("pkg")));
+
+        fs::write(&copy.destination, "edited").unwrap();
+        assert!(!copy.is_owned(&dir.join("pkg")));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn package(name: &str, source: &str, subdir: Option<&str>) -> InstalledPackage {
+        InstalledPackage {
+            name: name.to_string(),
+            version: "1.0.0".to_string(),
+            kind: SourceKind::Path,
+            source: source.to_string(),
+            path: PathBuf::from(source),
+            mode: InstallMode::Symlink,
+            subdir: subdir.map(PathBuf::from),
+            commit: None,
+            git_ref: None,
+            version_req: None,
+            dependencies: Vec::new(),
+            env: None,
+            targets: Vec::new(),
+            links: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn refuses_a_name_registered_to_another_source() {
+        let mut registry = Registry::default();
+        registry.record(package("pkg", "/srv/pkg", None));
+
+        assert!(registry
+            .get_same_source("pkg", "/srv/pkg", None)
+            .unwrap()
+            .is_some());
+        assert!(registry
+            .get_same_source("other", "/srv/other/pkg", None)
+            .unwrap()
+            .is_none());
+        assert!(registry
+            .get_same_source("pkg", "/srv/other/pkg", None)
+            .is_err());
+        assert!(registry
+            .get_same_source("pkg", "/srv/pkg", Some(Path::new("sub")))
+            .is_err());
+    }
+
+    #[test]
+    fn forgets_only_the_dropped_targets() {
+        let target = |user_files: &str| InstalledTarget {
+            igor_version: "9".to_string(),
+            user_files: PathBuf::from(user_files),
+        };
+        let link = |destination: &str| InstalledLink {
+            source: PathBuf::from("/srv/pkg/a.ipf"),
+            destination: PathBuf::from(destination),
+            hash: None,
+        };
+        let mut registry = Registry::default();
+        registry.record(InstalledPackage {
+            targets: vec![target("/uf/9"), target("/uf/10")],
+            links: vec![link("/uf/9/a.ipf"), link("/uf/10/a.ipf")],
+            ..package("pkg", "/srv/pkg", None)
+        });
+
+        registry.forget_targets("pkg", &[PathBuf::from("/uf/9")]);
+        let pkg = registry.find("pkg").unwrap();
+        assert_eq!(pkg.user_files(), vec![PathBuf::from("/uf/10")]);
+        assert_eq!(pkg.links.len(), 1);
+        assert_eq!(pkg.links[0].destination, Path::new("/uf/10/a.ipf"));
+    }
+}
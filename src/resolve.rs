@@ -0,0 +1,330 @@
+use crate::git::{
+    clone_repository_into_igor, split_url_ref, split_url_version, CloneOptions, GitRef, GitSource,
+    RefRequest,
+};
+use crate::manifest::{is_relative_inside, Manifest};
+use crate::progress::message;
+use crate::registry::{InstalledPackage, Registry, SourceKind};
+use anyhow::{bail, Context, Result};
+use semver::VersionReq;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+// A package whose sources are on disk, ready to be linked
+pub struct FetchedPackage {
+    pub kind: SourceKind,
+    // Git URL without any `#ref`, or the canonical local path
+    pub source: String,
+    // Package folder: the clone or local folder, or the subdirectory of it that was asked for
+    pub dir: PathBuf,
+    // Clone or local folder the package lives in
+    pub root: PathBuf,
+    pub subdir: Option<PathBuf>,
+    pub git_ref: Option<GitRef>,
+    // Semver requirement the checked out tag was chosen by
+    pub version_req: Option<VersionReq>,
+    pub manifest: Manifest,
+}
+
+// Clone or locate a package and read its manifest
+pub fn fetch_package(
+    repo_path: &str,
+    git_ref: Option<GitRef>,
+    version_req: Option<VersionReq>,
+    options: &CloneOptions,
+) -> Result<FetchedPackage> {
+    if let Some(subdir) = &options.subdir {
+        if !is_relative_inside(subdir) {
+            bail!(
+                "Subdirectory {:?} must be a relative path inside the package",
+                subdir
+            );
+        }
+    }
+    let (repo_url, fragment) = split_url_ref(repo_path);
+    let (git_source, url_version) = match GitSource::parse(repo_url) {
+        Some(_) => {
+            let (url, url_version) = split_url_version(repo_url)?;
+            (GitSource::parse(url), url_version)
+        }
+        None if repo_url.starts_with("gh:") => bail!("Expected gh:owner/repo, got {}", repo_url),
+        None => (None, None),
+    };
+    let version_req = match (version_req, url_version) {
+        (Some(_), Some(_)) => bail!("Give the version either after '@' or as an option, not both"),
+        (version_req, url_version) => version_req.or(url_version),
+    };
+    let request = match (git_ref, fragment, &version_req) {
+        (Some(git_ref), None, None) => Some(RefRequest::Exact(git_ref)),
+        (None, Some(name), None) => Some(RefRequest::Named(name.to_string())),
+        (None, None, Some(req)) => Some(RefRequest::Version(req.clone())),
+        (None, None, None) => None,
+        _ => bail!("Give only one of a branch, tag, rev, '#ref' or version requirement"),
+    };
+    let (repo, git_ref) = match &git_source {
+        Some(git_source) => clone_repository_into_igor(git_source, request.as_ref(), options)?,
+        None => (Path::new(repo_path).to_path_buf(), None),
+    };
+
+    let root = repo
+        .canonicalize()
+        .with_context(|| format!("Package {:?} was not found", repo_path))?;
+    let dir = match &options.subdir {
+        Some(subdir) => {
+            let dir = root.join(subdir);
+            if !dir.is_dir() {
+                bail!("{:?} has no subdirectory {:?}", repo_path, subdir);
+            }
+            dir
+        }
+        None => root.clone(),
+    };
+    let (kind, source) = match git_source {
+        Some(git_source) => (SourceKind::Git, git_source.url),
+        None => (SourceKind::Path, root.to_string_lossy().into_owned()),
+    };
+    let manifest = Manifest::load(&dir)?;
+
+    Ok(FetchedPackage {
+        kind,
+        source,
+        dir,
+        root,
+        subdir: options.subdir.clone(),
+        git_ref,
+        version_req,
+        manifest,
+    })
+}
+
+// What a package asked for when it declared a dependency
+#[derive(PartialEq, Eq)]
+struct Requirement {
+    source: String,
+    git_ref: Option<GitRef>,
+    version_req: Option<VersionReq>,
+    subdir: Option<PathBuf>,
+    required_by: String,
+}
+
+// Fetch the dependencies of `root` recursively and order them so every package
+// comes after the packages it depends on, with `root` last
+//
+// Dependencies other installed packages rely on must be asked for the way they were
+// installed, so installing `root` cannot move them out from under those packages.
+pub fn resolve_dependencies(
+    root: FetchedPackage,
+    registry: &Registry,
+) -> Result<Vec<FetchedPackage>> {
+    let mut stack = vec![root.manifest.package.name.clone()];
+    let mut requirements = BTreeMap::new();
+    let mut ordered = Vec::new();
+
+    visit(&root, registry, &mut stack, &mut requirements, &mut ordered)?;
+    ordered.push(root);
+    Ok(ordered)
+}
+
+// Depth-first walk of the dependency graph
+fn visit(
+    package: &FetchedPackage,
+    registry: &Registry,
+    stack: &mut Vec<String>,
+    requirements: &mut BTreeMap<String, Requirement>,
+    ordered: &mut Vec<FetchedPackage>,
+) -> Result<()> {
+    let dependent = &package.manifest.package.name;
+
+    for (name, dependency) in &package.manifest.dependencies {
+        if let Some(start) = stack.iter().position(|entry| entry == name) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(name.clone());
+            bail!("Dependency cycle: {}", cycle.join(" -> "));
+        }
+
+        let requirement = Requirement {
+            source: normalize_source(&dependency.source(&package.dir)),
+            git_ref: dependency.git_ref(),
+            version_req: dependency.version_req(),
+            subdir: dependency.subdir.clone(),
+            required_by: dependent.clone(),
+        };
+        if let Some(existing) = requirements.get(name) {
+            if existing.source != requirement.source
+                || existing.git_ref != requirement.git_ref
+                || existing.version_req != requirement.version_req
+                || existing.subdir != requirement.subdir
+            {
+                bail!(
+                    "Conflicting requirements for {}: {} wants {}, {} wants {}",
+                    name,
+                    existing.required_by,
+                    describe(existing),
+                    requirement.required_by,
+                    describe(&requirement)
+                );
+            }
+            continue;
+        }
+        if let Some(installed) = registry.get(name) {
+            let others: Vec<&str> = registry
+                .dependents(name)
+                .into_iter()
+                .filter(|other| !stack.iter().any(|entry| entry == other))
+                .collect();
+            if !others.is_empty() && !satisfied_by(&requirement, installed) {
+                bail!(
+                    "Conflicting requirements for {}: {} installed it as {}, {} wants {}",
+                    name,
+                    others.join(", "),
+                    describe_installed(installed),
+                    requirement.required_by,
+                    describe(&requirement)
+                );
+            }
+        }
+
+        message!("Resolving dependency {} of {}", name, dependent);
+        let fetched = fetch_package(
+            &dependency.source(&package.dir),
+            dependency.git_ref(),
+            dependency.version_req(),
+            &CloneOptions {
+                subdir: dependency.subdir.clone(),
+                ..CloneOptions::default()
+            },
+        )?;
+        if &fetched.manifest.package.name != name {
+            bail!(
+                "Dependency {} of {} is actually package {}",
+                name,
+                dependent,
+                fetched.manifest.package.name
+            );
+        }
+        requirements.insert(name.clone(), requirement);
+
+        stack.push(name.clone());
+        visit(&fetched, registry, stack, requirements, ordered)?;
+        stack.pop();
+        ordered.push(fetched);
+    }
+    Ok(())
+}
+
+// Source as the registry records it: a git URL with shorthand expanded, or a canonical
+// path, so different spellings of the same source match
+pub fn normalize_source(source: &str) -> String {
+    if let Some(git_source) = GitSource::parse(source) {
+        return git_source.url;
+    }
+    Path::new(source)
+        .canonicalize()
+        .map(|path| path.to_string_lossy().into_owned())
+        .unwrap_or_else(|_| source.to_string())
+}
+
+// Whether installing a requirement would leave an installed package where it is, or
+// only move it within the version requirement it was installed with
+fn satisfied_by(requirement: &Requirement, installed: &InstalledPackage) -> bool {
+    if requirement.source != installed.source || requirement.subdir != installed.subdir {
+        return false;
+    }
+    let installed_req = installed
+        .version_req
+        .as_deref()
+        .and_then(|req| VersionReq::parse(req).ok());
+    match (&requirement.git_ref, &requirement.version_req) {
+        (Some(git_ref), _) => {
+            installed_req.is_none() && installed.git_ref.as_ref() == Some(git_ref)
+        }
+        (None, Some(req)) => installed_req.as_ref() == Some(req),
+        // Without a ref an existing clone is used as it is
+        (None, None) => true,
+    }
+}
+
+// Human readable form of an installed package's source for error messages
+fn describe_installed(installed: &InstalledPackage) -> String {
+    let source = installed.describe_source();
+    match (&installed.version_req, &installed.git_ref) {
+        (Some(req), _) => format!("{}@{}", source, req),
+        (None, Some(git_ref)) => format!("{} at {}", source, git_ref),
+        (None, None) => source,
+    }
+}
+
+// Human readable form of a requirement for error messages
+fn describe(requirement: &Requirement) -> String {
+    let source = match &requirement.subdir {
+        Some(subdir) => format!("{} ({})", requirement.source, subdir.display()),
+        None => requirement.source.clone(),
+    };
+    match (&requirement.git_ref, &requirement.version_req) {
+        (Some(git_ref), _) => format!("{} at {}", source, git_ref),
+        (None, Some(req)) => format!("{}@{}", source, req),
+        (None, None) => source,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::link::InstallMode;
+
+    const UTILS: &str = "https://github.com/o/utils.git";
+
+    fn requirement(git_ref: Option<GitRef>, version_req: Option<&str>) -> Requirement {
+        Requirement {
+            source: UTILS.to_string(),
+            git_ref,
+            version_req: version_req.map(|req| VersionReq::parse(req).unwrap()),
+            subdir: None,
+            required_by: "x".to_string(),
+        }
+    }
+
+    fn installed(git_ref: GitRef, version_req: Option<&str>) -> InstalledPackage {
+        InstalledPackage {
+            name: "utils".to_string(),
+            version: "1.4.0".to_string(),
+            kind: SourceKind::Git,
+            source: UTILS.to_string(),
+            path: PathBuf::from("/home/me/.igor/utils"),
+            mode: InstallMode::Symlink,
+            subdir: None,
+            commit: None,
+            git_ref: Some(git_ref),
+            version_req: version_req.map(str::to_string),
+            dependencies: Vec::new(),
+            env: None,
+            targets: Vec::new(),
+            links: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn same_version_requirement_is_satisfied() {
+        let utils = installed(GitRef::Tag("v1.4.0".to_string()), Some("^1"));
+        assert!(satisfied_by(&requirement(None, Some("^1")), &utils));
+        assert!(!satisfied_by(&requirement(None, Some("^2")), &utils));
+        assert!(satisfied_by(&requirement(None, None), &utils));
+    }
+
+    #[test]
+    fn exact_refs_must_match() {
+        let utils = installed(GitRef::Tag("v1.4.0".to_string()), None);
+        let same = requirement(Some(GitRef::Tag("v1.4.0".to_string())), None);
+        let other = requirement(Some(GitRef::Tag("v2.0.0".to_string())), None);
+        assert!(satisfied_by(&same, &utils));
+        assert!(!satisfied_by(&other, &utils));
+    }
+
+    #[test]
+    fn other_sources_are_not_satisfied() {
+        let utils = installed(GitRef::Branch("main".to_string()), None);
+        let mut fork = requirement(None, None);
+        fork.source = "https://github.com/fork/utils.git".to_string();
+        assert!(!satisfied_by(&fork, &utils));
+    }
+}
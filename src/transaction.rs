@@ -0,0 +1,654 @@
+use crate::config::igor_home;
+use crate::git;
+use crate::link::{self, InstallMode};
+use crate::registry::{write_atomic, REGISTRY_FILE};
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Journal of the transaction in progress, under ~/.igor
+const JOURNAL_FILE: &str = "journal.toml";
+
+// Suffix of the hidden names removed files wait under until the transaction commits
+const STASH_SUFFIX: &str = "ipac-stash";
+
+// One change to the User Files folders
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "action", rename_all = "kebab-case")]
+pub enum Operation {
+    // Create a symbolic link, copy or hard link from `source` in the package
+    Link {
+        source: PathBuf,
+        destination: PathBuf,
+        mode: InstallMode,
+    },
+    // Remove a link or copy ipac installed, or a file in the way with `--force`
+    Remove {
+        path: PathBuf,
+    },
+    // Rename a file in the way with `--backup`
+    Backup {
+        path: PathBuf,
+        backup: PathBuf,
+    },
+    // Leave what is at `path` alone, saying why
+    Leave {
+        path: PathBuf,
+        reason: String,
+    },
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Operation::Link {
+                source,
+                destination,
+                mode: InstallMode::Symlink,
+            } => write!(f, "Link {:?} to {:?}", destination, source),
+            Operation::Link {
+                source,
+                destination,
+                mode: InstallMode::Copy,
+            } => write!(f, "Copy {:?} to {:?}", source, destination),
+            Operation::Link {
+                source,
+                destination,
+                mode: InstallMode::Hardlink,
+            } => write!(f, "Hard link {:?} to {:?}", destination, source),
+            Operation::Remove { path } => write!(f, "Remove {:?}", path),
+            Operation::Backup { path, backup } => write!(f, "Move {:?} to {:?}", path, backup),
+            Operation::Leave { path, reason } => write!(f, "Leave {:?}: {}", path, reason),
+        }
+    }
+}
+
+// What a transaction has done so far, persisted so an interrupted run can be undone
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Journal {
+    // Contents of the registry before the transaction, None when there was none
+    registry: Option<String>,
+    // Set once every change is made, so an interrupted commit is finished, not undone
+    #[serde(default)]
+    committed: bool,
+    // User Files folders to tidy up empty subfolders in on commit
+    #[serde(default)]
+    user_files: Vec<PathBuf>,
+    #[serde(default, rename = "step")]
+    steps: Vec<Step>,
+}
+
+// A change that was started, with what it takes to undo it
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(tag = "undo", rename_all = "kebab-case")]
+enum Step {
+    // A folder was created to hold links
+    CreatedFolder { path: PathBuf },
+    // A link or copy was created
+    Created { path: PathBuf },
+    // Something was moved out of the way, to be deleted on commit
+    Stashed { path: PathBuf, stash: PathBuf },
+    // Something was renamed to a backup that stays after commit
+    MovedAside { path: PathBuf, backup: PathBuf },
+    // A package clone was moved off the commit it was at
+    MovedClone { path: PathBuf, from: String },
+}
+
+// A set of changes to the User Files folders, the registry and the package clones
+// that either all happen or are all undone
+//
+// Every step is written to the journal before it is carried out. Removed files are
+// only renamed until the transaction commits, so rolling back can restore them.
+pub struct Transaction {
+    // Folder holding the journal and the registry: ~/.igor
+    dir: PathBuf,
+    journal: Journal,
+}
+
+impl Transaction {
+    // Start a transaction, first undoing any that an earlier run left unfinished
+    pub fn begin(user_files: &[PathBuf]) -> Result<Transaction> {
+        Transaction::begin_in(&igor_home(), user_files)
+    }
+
+    // Start a transaction whose journal and registry are in `dir`
+    fn begin_in(dir: &Path, user_files: &[PathBuf]) -> Result<Transaction> {
+        recover_in(dir)?;
+        let path = dir.join(REGISTRY_FILE);
+        let registry = if path.exists() {
+            Some(fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?)
+        } else {
+            None
+        };
+        let transaction = Transaction {
+            dir: dir.to_path_buf(),
+            journal: Journal {
+                registry,
+                committed: false,
+                user_files: user_files.to_vec(),
+                steps: Vec::new(),
+            },
+        };
+        transaction.save()?;
+        Ok(transaction)
+    }
+
+    // Carry out one operation, journaling it first
+    pub fn apply(&mut self, operation: &Operation) -> Result<()> {
+        match operation {
+            Operation::Link {
+                source,
+                destination,
+                mode,
+            } => {
+                // Igor creates these folders on first launch, which may not have happened yet
+                self.create_parent(destination)?;
+                self.record(Step::Created {
+                    path: destination.clone(),
+                })?;
+                create_link(source, destination, *mode)
+            }
+            Operation::Remove { path } => {
+                println!("Removing {:?}", path);
+                let stash = stash_path(path);
+                self.record(Step::Stashed {
+                    path: path.clone(),
+                    stash: stash.clone(),
+                })?;
+                fs::rename(path, &stash).with_context(|| format!("Failed to remove {:?}", path))
+            }
+            Operation::Backup { path, backup } => {
+                println!("Moving {:?} to {:?}", path, backup);
+                self.create_parent(backup)?;
+                self.record(Step::MovedAside {
+                    path: path.clone(),
+                    backup: backup.clone(),
+                })?;
+                fs::rename(path, backup)
+                    .with_context(|| format!("Failed to move {:?} to {:?}", path, backup))
+            }
+            Operation::Leave { path, reason } => {
+                println!("Leaving {:?}: {}", path, reason);
+                Ok(())
+            }
+        }
+    }
+
+    // Move the clone at `repo_dir` from commit `from` to `to`, journaling it first so
+    // rolling back puts the clone back at `from`
+    pub fn move_clone(&mut self, repo_dir: &Path, from: &str, to: &str) -> Result<()> {
+        self.record(Step::MovedClone {
+            path: repo_dir.to_path_buf(),
+            from: from.to_string(),
+        })?;
+        git::move_clone(repo_dir, to)
+    }
+
+    // Create the missing folders above `path`, journaling each one
+    fn create_parent(&mut self, path: &Path) -> Result<()> {
+        let Some(dir) = path.parent() else {
+            return Ok(());
+        };
+        let mut missing: Vec<_> = dir
+            .ancestors()
+            .take_while(|folder| !folder.exists())
+            .map(Path::to_path_buf)
+            .collect();
+        missing.reverse();
+        if !missing.is_empty() {
+            self.journal
+                .steps
+                .extend(missing.into_iter().map(|path| Step::CreatedFolder { path }));
+            self.save()?;
+            fs::create_dir_all(dir).with_context(|| format!("Failed to create {:?}", dir))?;
+        }
+        Ok(())
+    }
+
+    // Carry out operations in order, stopping at the first failure
+    pub fn apply_all(&mut self, operations: &[Operation]) -> Result<()> {
+        for operation in operations {
+            self.apply(operation)?;
+        }
+        Ok(())
+    }
+
+    // Commit when `result` is Ok, otherwise undo everything and pass the error on
+    pub fn finish(self, result: Result<()>) -> Result<()> {
+        match result {
+            Ok(()) => self.commit(),
+            Err(err) => {
+                self.rollback()?;
+                Err(err)
+            }
+        }
+    }
+
+    // Make the changes final: delete what was stashed and drop the journal
+    //
+    // The journal is marked committed first, so a run killed while deleting stashes
+    // leaves the next run to finish deleting them rather than undo the rest.
+    fn commit(mut self) -> Result<()> {
+        self.journal.committed = true;
+        self.save()?;
+        finish_commit(&self.journal, &self.dir)
+    }
+
+    // Undo every step, newest first, and restore the registry
+    fn rollback(self) -> Result<()> {
+        let undone = undo(self.journal, &self.dir)?;
+        println!("Rolled back {} change(s) to the User Files folders", undone);
+        Ok(())
+    }
+
+    // Add a step to the journal and persist it
+    fn record(&mut self, step: Step) -> Result<()> {
+        self.journal.steps.push(step);
+        self.save()
+    }
+
+    // Persist the journal
+    fn save(&self) -> Result<()> {
+        let path = journal_path(&self.dir);
+        let contents = toml::to_string_pretty(&self.journal)?;
+        write_atomic(&path, &contents).with_context(|| format!("Failed to write {:?}", path))
+    }
+}
+
+// Undo a transaction an interrupted run left behind
+pub fn recover() -> Result<()> {
+    recover_in(&igor_home())
+}
+
+// Undo or finish the transaction whose journal is in `dir`
+fn recover_in(dir: &Path) -> Result<()> {
+    let path = journal_path(dir);
+    if !path.exists() {
+        return Ok(());
+    }
+    let contents =
+        fs::read_to_string(&path).with_context(|| format!("Failed to read {:?}", path))?;
+    let journal: Journal =
+        toml::from_str(&contents).with_context(|| format!("Failed to parse {:?}", path))?;
+
+    if journal.committed {
+        println!("Finishing changes left by an interrupted run");
+        return finish_commit(&journal, dir);
+    }
+    println!("Undoing changes left by an interrupted run");
+    let undone = undo(journal, dir)?;
+    println!("Rolled back {} change(s) to the User Files folders", undone);
+    Ok(())
+}
+
+// Delete what a committed transaction stashed, then the journal
+fn finish_commit(journal: &Journal, dir: &Path) -> Result<()> {
+    for step in &journal.steps {
+        if let Step::Stashed { path, stash } = step {
+            if fs::symlink_metadata(stash).is_ok() {
+                link::remove_existing(stash)?;
+            }
+            prune_empty_folders(path, &journal.user_files);
+        }
+    }
+    remove_journal(dir)
+}
+
+// Reverse the steps of a journal and restore the registry it saved, returning how
+// many steps were undone
+//
+// Steps may have been journaled without being carried out, so each undo checks
+// what is actually on disk.
+fn undo(journal: Journal, dir: &Path) -> Result<usize> {
+    let mut undone = 0;
+    for step in journal.steps.iter().rev() {
+        match step {
+            Step::CreatedFolder { path } => {
+                let _ = fs::remove_dir(path);
+            }
+            Step::Created { path } => {
+                if fs::symlink_metadata(path).is_ok() {
+                    link::remove_existing(path)?;
+                    undone += 1;
+                }
+            }
+            Step::Stashed { path, stash: moved }
+            | Step::MovedAside {
+                path,
+                backup: moved,
+            } => {
+                if fs::symlink_metadata(moved).is_ok() && fs::symlink_metadata(path).is_err() {
+                    fs::rename(moved, path)
+                        .with_context(|| format!("Failed to restore {:?}", path))?;
+                    undone += 1;
+                }
+            }
+            Step::MovedClone { path, from } => git::move_clone(path, from)
+                .with_context(|| format!("Failed to move {:?} back to {:.8}", path, from))?,
+        }
+    }
+
+    let path = dir.join(REGISTRY_FILE);
+    match &journal.registry {
+        Some(contents) => write_atomic(&path, contents)
+            .with_context(|| format!("Failed to restore {:?}", path))?,
+        None if path.exists() => {
+            fs::remove_file(&path).with_context(|| format!("Failed to remove {:?}", path))?
+        }
+        None => {}
+    }
+    remove_journal(dir)?;
+    Ok(undone)
+}
+
+// Create a symbolic link, copy or hard link from a file or folder in the package to
+// its Igor destination
+fn create_link(source: &Path, destination: &Path, mode: InstallMode) -> Result<()> {
+    match mode {
+        InstallMode::Symlink => {
+            println!(
+                "Creating symbolic link from {:?} to {:?}",
+                source, destination
+            );
+            if source.is_dir() {
+                link::link_dir(source, destination)
+            } else {
+                link::link_file(source, destination)
+            }
+        }
+        InstallMode::Copy => {
+            println!("Copying {:?} to {:?}", source, destination);
+            link::copy_file(source, destination)
+        }
+        InstallMode::Hardlink => {
+            println!("Creating hard link from {:?} to {:?}", source, destination);
+            link::hard_link(source, destination)
+        }
+    }
+}
+
+// Hidden sibling name to keep a removed file under until commit
+fn stash_path(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let mut stash = path.with_file_name(format!(".{}.{}", name, STASH_SUFFIX));
+    let mut number = 1;
+    while fs::symlink_metadata(&stash).is_ok() {
+        number += 1;
+        stash = path.with_file_name(format!(".{}.{}-{}", name, STASH_SUFFIX, number));
+    }
+    stash
+}
+
+// Remove the empty folders above `destination`, stopping at the top-level folders of
+// the User Files folders such as "Igor Procedures"
+fn prune_empty_folders(destination: &Path, user_files: &[PathBuf]) {
+    for folder in destination.ancestors().skip(1) {
+        let nested = user_files.iter().any(|user_files| {
+            folder
+                .strip_prefix(user_files)
+                .is_ok_and(|folder| folder.components().count() > 1)
+        });
+        if !nested || fs::remove_dir(folder).is_err() {
+            break;
+        }
+    }
+}
+
+// Delete the journal once its transaction is finished
+fn remove_journal(dir: &Path) -> Result<()> {
+    let path = journal_path(dir);
+    if path.exists() {
+        fs::remove_file(&path).with_context(|| format!("Failed to remove {:?}", path))?;
+    }
+    Ok(())
+}
+
+// Location of the journal file in `dir`
+fn journal_path(dir: &Path) -> PathBuf {
+    dir.join(JOURNAL_FILE)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn journal_round_trips_with_its_commit_mark() {
+        let journal = Journal {
+            registry: Some("[[package]]\n".to_string()),
+            committed: true,
+            user_files: vec![PathBuf::from("/uf/Igor Pro 10 User Files")],
+            steps: vec![Step::Stashed {
+                path: PathBuf::from("/uf/a.ipf"),
+                stash: PathBuf::from("/uf/.a.ipf.ipac-stash"),
+            }],
+        };
+        let read: Journal = toml::from_str(&toml::to_string_pretty(&journal).unwrap()).unwrap();
+        assert!(read.committed);
+        assert_eq!(read.user_files, journal.user_files);
+        assert_eq!(read.steps.len(), 1);
+    }
+
+    #[test]
+    fn journals_from_before_the_commit_mark_are_undone() {
+        let read: Journal =
+            toml::from_str("[[step]]\nundo = \"created\"\npath = \"/uf/a.ipf\"\n").unwrap();
+        assert!(!read.committed);
+    }
+
+    // A package, a User Files folder with files already in it, and a home for the
+    // journal and registry
+    struct Tree {
+        dir: PathBuf,
+        home: PathBuf,
+        package: PathBuf,
+        user_files: PathBuf,
+        procedures: PathBuf,
+    }
+
+    impl Tree {
+        fn new(name: &str) -> Tree {
+            let dir =
+                std::env::temp_dir().join(format!("ipac-test-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            let tree = Tree {
+                home: dir.join("home"),
+                package: dir.join("pkg"),
+                user_files: dir.join("uf"),
+                procedures: dir.join("uf").join("User Procedures"),
+                dir,
+            };
+            fs::create_dir_all(&tree.home).unwrap();
+            fs::create_dir_all(&tree.package).unwrap();
+            fs::create_dir_all(tree.procedures.join("old")).unwrap();
+            fs::write(tree.package.join("a.ipf"), "new").unwrap();
+            fs::write(tree.procedures.join("gone.ipf"), "gone").unwrap();
+            fs::write(tree.procedures.join("mine.ipf"), "mine").unwrap();
+            fs::write(tree.procedures.join("old").join("b.ipf"), "old").unwrap();
+            tree
+        }
+
+        fn begin(&self) -> Transaction {
+            Transaction::begin_in(&self.home, std::slice::from_ref(&self.user_files)).unwrap()
+        }
+
+        // Remove two files, back one up and copy one into a folder that is not there yet
+        fn apply(&self, transaction: &mut Transaction) {
+            transaction
+                .apply_all(&[
+                    Operation::Remove {
+                        path: self.procedures.join("gone.ipf"),
+                    },
+                    Operation::Remove {
+                        path: self.procedures.join("old").join("b.ipf"),
+                    },
+                    Operation::Backup {
+                        path: self.procedures.join("mine.ipf"),
+                        backup: self.procedures.join("mine.ipf.ipac-backup"),
+                    },
+                    Operation::Link {
+                        source: self.package.join("a.ipf"),
+                        destination: self.procedures.join("pkg").join("a.ipf"),
+                        mode: InstallMode::Copy,
+                    },
+                ])
+                .unwrap();
+        }
+
+        fn read(&self, path: &str) -> Option<String> {
+            fs::read_to_string(self.procedures.join(path)).ok()
+        }
+
+        fn registry(&self) -> Option<String> {
+            fs::read_to_string(self.home.join(REGISTRY_FILE)).ok()
+        }
+
+        fn has_journal(&self) -> bool {
+            journal_path(&self.home).exists()
+        }
+
+        // Whether a stashed file is left anywhere in User Procedures
+        fn has_stash(&self) -> bool {
+            [self.procedures.clone(), self.procedures.join("old")]
+                .iter()
+                .filter_map(|folder| fs::read_dir(folder).ok())
+                .flatten()
+                .any(|entry| {
+                    entry
+                        .unwrap()
+                        .file_name()
+                        .to_string_lossy()
+                        .contains(STASH_SUFFIX)
+                })
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn rollback_restores_everything() {
+        let tree = Tree::new("rollback");
+        fs::write(tree.home.join(REGISTRY_FILE), "before").unwrap();
+        let mut transaction = tree.begin();
+        tree.apply(&mut transaction);
+        fs::write(tree.home.join(REGISTRY_FILE), "after").unwrap();
+
+        assert!(transaction.finish(Err(anyhow::anyhow!("failed"))).is_err());
+        assert_eq!(tree.read("gone.ipf").as_deref(), Some("gone"));
+        assert_eq!(tree.read("old/b.ipf").as_deref(), Some("old"));
+        assert_eq!(tree.read("mine.ipf").as_deref(), Some("mine"));
+        assert!(tree.read("mine.ipf.ipac-backup").is_none());
+        assert!(!tree.procedures.join("pkg").exists());
+        assert_eq!(tree.registry().as_deref(), Some("before"));
+        assert!(!tree.has_stash());
+        assert!(!tree.has_journal());
+    }
+
+    #[test]
+    fn commit_deletes_stashes_and_keeps_changes() {
+        let tree = Tree::new("commit");
+        let mut transaction = tree.begin();
+        tree.apply(&mut transaction);
+        fs::write(tree.home.join(REGISTRY_FILE), "after").unwrap();
+
+        transaction.finish(Ok(())).unwrap();
+        assert!(tree.read("gone.ipf").is_none());
+        assert_eq!(tree.read("mine.ipf.ipac-backup").as_deref(), Some("mine"));
+        assert_eq!(tree.read("pkg/a.ipf").as_deref(), Some("new"));
+        assert_eq!(tree.registry().as_deref(), Some("after"));
+        // Emptied subfolders go, the top-level Igor folders stay
+        assert!(!tree.procedures.join("old").exists());
+        assert!(tree.procedures.exists());
+        assert!(!tree.has_stash());
+        assert!(!tree.has_journal());
+    }
+
+    #[test]
+    fn recovery_undoes_an_interrupted_transaction() {
+        let tree = Tree::new("recover-undo");
+        let mut transaction = tree.begin();
+        tree.apply(&mut transaction);
+        fs::write(tree.home.join(REGISTRY_FILE), "after").unwrap();
+        // The run dies before finishing
+        drop(transaction);
+        assert!(tree.has_journal());
+
+        recover_in(&tree.home).unwrap();
+        assert_eq!(tree.read("gone.ipf").as_deref(), Some("gone"));
+        assert_eq!(tree.read("mine.ipf").as_deref(), Some("mine"));
+        assert!(!tree.procedures.join("pkg").exists());
+        // There was no registry before the transaction
+        assert!(tree.registry().is_none());
+        assert!(!tree.has_stash());
+        assert!(!tree.has_journal());
+    }
+
+    #[test]
+    fn recovery_finishes_a_committed_transaction() {
+        let tree = Tree::new("recover-commit");
+        let mut transaction = tree.begin();
+        tree.apply(&mut transaction);
+        // The run dies after marking the journal committed but before deleting stashes
+        transaction.journal.committed = true;
+        transaction.save().unwrap();
+        drop(transaction);
+        assert!(tree.has_stash());
+
+        recover_in(&tree.home).unwrap();
+        assert!(tree.read("gone.ipf").is_none());
+        assert_eq!(tree.read("pkg/a.ipf").as_deref(), Some("new"));
+        assert!(!tree.has_stash());
+        assert!(!tree.has_journal());
+    }
+
+    #[test]
+    fn rollback_moves_a_clone_back() {
+        let tree = Tree::new("move-clone");
+        let clone = tree.dir.join("clone");
+        let repo = git2::Repository::init(&clone).unwrap();
+        let signature = git2::Signature::now("ipac", "ipac@example.com").unwrap();
+        let mut commits: Vec<git2::Oid> = Vec::new();
+        for contents in ["one", "two"] {
+            fs::write(clone.join("a.ipf"), contents).unwrap();
+            let mut index = repo.index().unwrap();
+            index.add_path(Path::new("a.ipf")).unwrap();
+            index.write().unwrap();
+            let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+            let parents: Vec<_> = commits
+                .iter()
+                .map(|id| repo.find_commit(*id).unwrap())
+                .collect();
+            let parents: Vec<_> = parents.iter().collect();
+            let id = repo
+                .commit(
+                    Some("HEAD"),
+                    &signature,
+                    &signature,
+                    contents,
+                    &tree,
+                    &parents,
+                )
+                .unwrap();
+            commits.push(id);
+        }
+        let (one, two) = (commits[0].to_string(), commits[1].to_string());
+
+        let mut transaction = tree.begin();
+        transaction.move_clone(&clone, &two, &one).unwrap();
+        assert_eq!(fs::read_to_string(clone.join("a.ipf")).unwrap(), "one");
+
+        assert!(transaction.finish(Err(anyhow::anyhow!("failed"))).is_err());
+        assert_eq!(fs::read_to_string(clone.join("a.ipf")).unwrap(), "two");
+        assert_eq!(git::head_commit(&clone), Some(two));
+        assert!(repo.head().unwrap().is_branch());
+    }
+}
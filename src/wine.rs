@@ -0,0 +1,66 @@
+use crate::igor::IgorRoot;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+// Collect the Wine prefixes to scan, dropping duplicates and folders that are not prefixes
+//
+// `explicit` prefixes come from the command line and config file. When `auto_detect`
+// is set, WINEPREFIX and the default ~/.wine prefix are scanned as well.
+pub fn wine_prefixes(explicit: Vec<PathBuf>, auto_detect: bool) -> Vec<PathBuf> {
+    let mut candidates = explicit;
+    if auto_detect {
+        if let Some(prefix) = std::env::var_os("WINEPREFIX") {
+            candidates.push(PathBuf::from(prefix));
+        }
+        if let Some(home) = dirs::home_dir() {
+            candidates.push(home.join(".wine"));
+        }
+    }
+
+    let mut prefixes: Vec<PathBuf> = Vec::new();
+    for candidate in candidates {
+        if !candidate.join("drive_c").is_dir() {
+            continue;
+        }
+        let prefix = candidate.canonicalize().unwrap_or(candidate);
+        if !prefixes.contains(&prefix) {
+            prefixes.push(prefix);
+        }
+    }
+    prefixes
+}
+
+// Build the discovery root for a Wine prefix, mirroring the native Windows layout
+pub fn wine_root(prefix: &Path) -> Option<IgorRoot> {
+    let drive_c = prefix.join("drive_c");
+    let documents = wine_documents_dir(&drive_c.join("users"))?;
+
+    Some(IgorRoot {
+        label: format!("wine {}", prefix.display()),
+        igor_dir: drive_c.join("Program Files").join("WaveMetrics"),
+        user_files_dir: documents.join("WaveMetrics"),
+    })
+}
+
+// Find the Documents folder of the Wine user, preferring the one named after $USER
+fn wine_documents_dir(users_dir: &Path) -> Option<PathBuf> {
+    let mut candidates = Vec::new();
+    if let Some(user) = std::env::var_os("USER").filter(|user| !user.is_empty()) {
+        candidates.push(users_dir.join(user));
+    }
+    if let Ok(entries) = fs::read_dir(users_dir) {
+        let mut others: Vec<_> = entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|path| path.is_dir() && !path.ends_with("Public"))
+            .collect();
+        others.sort();
+        candidates.extend(others);
+    }
+
+    // Recent Wine versions use "Documents", older ones "My Documents"
+    candidates
+        .iter()
+        .flat_map(|user| [user.join("Documents"), user.join("My Documents")])
+        .find(|documents| documents.is_dir())
+}